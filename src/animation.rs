@@ -1,21 +1,15 @@
-use std::{f32::consts::PI, time::Duration};
+use std::time::Duration;
 
-use glam::{Affine3A, Vec3};
+use glam::Affine3A;
 
-use crate::{
-    constants::{HEIGHT, WIDTH},
-    model::Model,
-};
+use crate::model::Model;
 
 /// called every tick
 pub fn update(x_radians: f32, y_radians: f32, model: &mut Model) {
     // let t = timing.time_since_start.as_secs_f32();
 
-    model.cube.transform = Affine3A::from_translation(Vec3::new(WIDTH as f32 / 2., HEIGHT as f32 / 2., 0.))
-            * Affine3A::from_rotation_x(x_radians + PI)
-            * Affine3A::from_rotation_y(y_radians)
-            // * Affine3A::from_scale(Vec3::splat(50.));
-            * Affine3A::from_scale(Vec3::splat(30.));
+    model.scene.transform =
+        Affine3A::from_rotation_x(x_radians) * Affine3A::from_rotation_y(y_radians);
 }
 
 pub struct Timing {
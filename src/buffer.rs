@@ -1,18 +1,47 @@
 use core::f32;
 
-use glam::Vec2;
+use glam::{Vec2, Vec3};
 use num_traits::ToBytes;
 
 use crate::{
-    color::{self, dither_mask_shader, flat_lit_shader, Material},
-    constants::{CLEAR_COLOR, COLOR_DEPTH},
-    geo::IndexedTriangle,
+    color::Palette,
+    constants::CLEAR_COLOR,
+    geo::Mesh,
     interpolate::{lerp, LerpIter},
-    poly::Tri,
+    poly::{Rasterizer, Tri, Varyings},
+    shader::{Fragment, FragmentShader},
 };
 
 //TODO: create a type for indexed colors
 
+/// How far in front of a surface a line is allowed to be hidden by it, so that
+/// edges drawn over their own triangles don't flicker
+const LINE_DEPTH_BIAS: f32 = 1e-4;
+
+/// One end of a horizontal span passed to `Buffer::h_line`
+#[derive(Debug, Clone, Copy)]
+pub struct SpanEnd {
+    pub x: f32,
+    pub z: f32,
+}
+
+impl SpanEnd {
+    /// `(z, x)`, for interpolating depth along x with `lerp`
+    fn zx(&self) -> Vec2 {
+        Vec2::new(self.z, self.x)
+    }
+}
+
+/// Identifies a triangle among all the meshes drawn in a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TriangleId {
+    /// position of the mesh in the list passed to `Buffer::finalize_render`.
+    /// A mesh drawn twice, like an instance, gets two ids.
+    pub mesh: usize,
+    /// index into the mesh's triangles
+    pub triangle: usize,
+}
+
 /// Contains the current frames data both as
 ///
 /// `canvas`: unscaled, indexed colored mode
@@ -20,57 +49,87 @@ pub struct Buffer {
     width: usize,
     height: usize,
     /// map color indicies to u32 rgb values
-    pub palette: [u32; COLOR_DEPTH as usize],
+    palette: Vec<u32>,
+    /// palette index of uncovered pixels, the closest to `CLEAR_COLOR`
+    clear_color: u8,
 
     /// User controlled, screen buffer, holding color palette indices
     /// Length is `width * height`
     pub canvas: Vec<u8>,
     z_buffer: Vec<f32>,
-    // TODO this index is per-mesh, so we need to specify a mesh
-    // in addition to the triangle index
-    tri_buffer: Vec<Option<usize>>,
+    tri_buffer: Vec<Option<TriangleId>>,
     light_buffer: Vec<f32>,
+    uv_buffer: Vec<Vec2>,
+    normal_buffer: Vec<Vec3>,
+    position_buffer: Vec<Vec3>,
+    /// Which triangle rasterizer `poly::draw_tri` uses
+    pub rasterizer: Rasterizer,
+    /// `fragment` only fills the z buffer and leaves the pixel uncovered, so
+    /// that a wireframe mesh hides what is behind it without being shaded
+    pub depth_only: bool,
 }
 
 impl Buffer {
     // TODO use stateful structs type pattern for render state?
-    pub fn finalize_render(&mut self, materials: &[Material], tris: &[IndexedTriangle]) {
-        // self.canvas = self.apply_fragment_shader(flat_lit_shader, materials, tris);
-        self.canvas = self.apply_fragment_shader(dither_mask_shader, materials, tris);
+    /// `meshes` are indexed by the mesh ids they were rendered with
+    pub fn finalize_render(&mut self, shader: &dyn FragmentShader, meshes: &[&Mesh]) {
+        self.canvas = self.apply_fragment_shader(shader, meshes);
     }
 
-    pub fn apply_fragment_shader<F>(
-        &self,
-        shader: F,
-        materials: &[Material],
-        tris: &[IndexedTriangle],
-    ) -> Vec<u8>
+    /// Shades every covered pixel with `shader`, or with its material's own
+    /// shader if it has one. Uncovered pixels get the clear color.
+    pub fn apply_fragment_shader<S>(&self, shader: &S, meshes: &[&Mesh]) -> Vec<u8>
     where
-        F: Fn(usize, usize, Option<&Material>, f32) -> u8,
+        S: FragmentShader + ?Sized,
     {
-        // need lighting and material values per pixel
-        self.tri_buffer
-            .iter()
-            .zip(self.light_buffer.iter())
-            .enumerate()
-            .map(|(i, (tri_idx, light))| (i % self.width, i / self.width, tri_idx, light))
-            .map(|(x, y, tri_idx, light)| {
-                let material = tri_idx.map(|i| &materials[tris[i].material_index]);
-                (shader)(x, y, material, *light)
+        (0..self.width * self.height)
+            .map(|i| match self.fragment_at(i, meshes) {
+                Some(fragment) => match fragment.material.shader {
+                    Some(material_shader) => material_shader.shade(&fragment),
+                    None => shader.shade(&fragment),
+                },
+                None => self.clear_color,
             })
             .collect()
     }
 
+    /// Gathers the G-buffer values at canvas index `i`
+    fn fragment_at<'a>(&self, i: usize, meshes: &[&'a Mesh]) -> Option<Fragment<'a>> {
+        let id = self.tri_buffer[i]?;
+        let mesh = meshes[id.mesh];
+        let tri = &mesh.triangles[id.triangle];
+        Some(Fragment {
+            x: i % self.width,
+            y: i / self.width,
+            depth: self.z_buffer[i],
+            mesh: id.mesh,
+            triangle: id.triangle,
+            material: &mesh.materials.materials[tri.material_index],
+            texture: mesh.texture.as_ref().filter(|_| tri.uv_index.is_some()),
+            normal: self.normal_buffer[i].normalize_or_zero(),
+            position: self.position_buffer[i],
+            uv: self.uv_buffer[i],
+            light: self.light_buffer[i],
+        })
+    }
+
     //    pub fn new
-    pub fn new(width: usize, height: usize, palette: [u32; COLOR_DEPTH as usize]) -> Self {
+    pub fn new(width: usize, height: usize, palette: &Palette) -> Self {
+        let clear_color = palette.nearest_to(CLEAR_COLOR);
         Buffer {
             width,
             height,
-            palette,
-            canvas: vec![CLEAR_COLOR; width * height],
+            palette: palette.colors.clone(),
+            clear_color,
+            canvas: vec![clear_color; width * height],
             z_buffer: vec![f32::NEG_INFINITY; width * height],
             tri_buffer: vec![None; width * height],
             light_buffer: vec![0.; width * height],
+            uv_buffer: vec![Vec2::ZERO; width * height],
+            normal_buffer: vec![Vec3::ZERO; width * height],
+            position_buffer: vec![Vec3::ZERO; width * height],
+            rasterizer: Rasterizer::default(),
+            depth_only: false,
         }
     }
 
@@ -84,11 +143,26 @@ impl Buffer {
         self.height
     }
 
-    pub fn tri_idx_at_pixel(&self, x: usize, y: usize) -> Option<usize> {
+    pub fn tri_id_at_pixel(&self, x: usize, y: usize) -> Option<TriangleId> {
         let i = y * self.width + x;
         self.tri_buffer[i]
     }
 
+    /// 0xRRGGBBAA colors that canvas indices refer to
+    pub fn palette(&self) -> &[u32] {
+        &self.palette
+    }
+
+    /// Switches to `palette`, which may have a different size
+    pub fn set_palette(&mut self, palette: &Palette) {
+        self.palette = palette.colors.clone();
+        self.clear_color = palette.nearest_to(CLEAR_COLOR);
+    }
+
+    pub fn clear_color(&self) -> u8 {
+        self.clear_color
+    }
+
     pub fn get_palette_rgb(&self) -> Vec<[u8; 4]> {
         self.canvas
             .iter()
@@ -97,7 +171,7 @@ impl Buffer {
     }
 
     pub fn clear_screen(&mut self) {
-        self.canvas.fill(CLEAR_COLOR);
+        self.canvas.fill(self.clear_color);
         self.z_buffer.fill(f32::NEG_INFINITY);
         self.tri_buffer.fill(None);
     }
@@ -110,6 +184,44 @@ impl Buffer {
         Self::pix_unchecked(self, x, y, color);
     }
 
+    /// Draws a line of `color` between screen space points `from` and `to`
+    /// straight onto the canvas, so it has to happen after `finalize_render`.
+    ///
+    /// With `depth_test`, pixels behind the surfaces in the z buffer are
+    /// skipped. The z buffer itself is left unchanged.
+    pub fn line(&mut self, from: Vec3, to: Vec3, color: u8, depth_test: bool) {
+        // Bresenham's line algorithm
+        let (mut x, mut y) = (from.x.round() as i32, from.y.round() as i32);
+        let (x_end, y_end) = (to.x.round() as i32, to.y.round() as i32);
+        let dx = (x_end - x).abs();
+        let dy = -(y_end - y).abs();
+        let step_x = if x < x_end { 1 } else { -1 };
+        let step_y = if y < y_end { 1 } else { -1 };
+        let mut error = dx + dy;
+
+        let steps = dx.max(-dy);
+        let z_values = LerpIter::new((0., from.z), (steps as f32, to.z), steps as usize + 1);
+        for (_, z) in z_values {
+            let on_screen =
+                (0..self.width as i32).contains(&x) && (0..self.height as i32).contains(&y);
+            if on_screen {
+                let i = y as usize * self.width + x as usize;
+                if !depth_test || z + LINE_DEPTH_BIAS >= self.z_buffer[i] {
+                    self.canvas[i] = color;
+                }
+            }
+            let doubled_error = 2 * error;
+            if doubled_error >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled_error <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
     fn pix_unchecked(&mut self, x: usize, y: usize, color: u8) {
         // update pixels
         self.canvas[y * self.width + x] = color;
@@ -128,16 +240,7 @@ impl Buffer {
         }
     }
 
-    pub fn h_line(
-        &mut self,
-        x1: f32,
-        x2: f32,
-        y: i32,
-        z1: f32,
-        z2: f32,
-        tri_idx: usize,
-        illumination: f32,
-    ) {
+    pub fn h_line(&mut self, left: SpanEnd, right: SpanEnd, y: i32, tri: &Tri) {
         let y = match usize::try_from(y) {
             Ok(val) => {
                 if val >= self.height {
@@ -149,18 +252,17 @@ impl Buffer {
             Err(_) => return,
         };
 
-        let x1_int = x1.ceil() as i32;
-        let x2_int = x2.floor() as i32 + 1;
+        let x1_int = left.x.ceil() as i32;
+        let x2_int = right.x.floor() as i32 + 1;
 
         let x_start = Self::clamp_i32(x1_int, 0, self.width);
         let x_end = Self::clamp_i32(x2_int, 0, self.width);
 
-        let z_start = lerp(Vec2::new(z1, x1), Vec2::new(z2, x2), x_start as f32);
-        let z_end = lerp(Vec2::new(z1, x1), Vec2::new(z2, x2), x_end as f32);
+        let z_start = lerp(left.zx(), right.zx(), x_start as f32);
+        let z_end = lerp(left.zx(), right.zx(), x_end as f32);
 
         let h_line_width = x_end - x_start;
 
-        let canvas_offset = y * self.width;
         let range = x_start..x_end;
 
         let z_values = LerpIter::new(
@@ -170,13 +272,115 @@ impl Buffer {
         );
 
         range.zip(z_values).for_each(|(x, (_, z))| {
-            //// Z buffer test
-            if z > self.z_buffer[canvas_offset + x] {
-                //// Update Canvas/Z-buffer
-                self.z_buffer[canvas_offset + x] = z;
-                self.tri_buffer[canvas_offset + x] = Some(tri_idx);
-                self.light_buffer[canvas_offset + x] = illumination;
-            }
+            let weights = tri.weights(Vec2::new(x as f32, y as f32));
+            self.fragment(x, y, z, tri.id, tri.varyings(weights));
         });
     }
+
+    /// Depth tests a single fragment at `x`,`y` and records it if it is closest
+    pub fn fragment(&mut self, x: usize, y: usize, z: f32, id: TriangleId, varyings: Varyings) {
+        let i = y * self.width + x;
+        //// Z buffer test
+        if z > self.z_buffer[i] {
+            //// Update Canvas/Z-buffer
+            self.z_buffer[i] = z;
+            if self.depth_only {
+                self.tri_buffer[i] = None;
+                return;
+            }
+            self.tri_buffer[i] = Some(id);
+            self.light_buffer[i] = varyings.light;
+            self.uv_buffer[i] = varyings.uv;
+            self.normal_buffer[i] = varyings.normal;
+            self.position_buffer[i] = varyings.position;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer() -> Buffer {
+        let palette = Palette {
+            colors: vec![0x000000FF, 0xFFFFFFFF],
+        };
+        Buffer::new(8, 8, &palette)
+    }
+
+    /// Covered pixels as (x, y), row by row
+    fn drawn(buffer: &Buffer) -> Vec<(usize, usize)> {
+        (0..buffer.width * buffer.height)
+            .filter(|&i| buffer.canvas[i] == 1)
+            .map(|i| (i % buffer.width, i / buffer.width))
+            .collect()
+    }
+
+    #[test]
+    fn line_covers_both_endpoints() {
+        let mut b = buffer();
+        b.line(Vec3::new(1., 1., 0.), Vec3::new(1., 1., 0.), 1, false);
+        assert_eq!(vec![(1, 1)], drawn(&b));
+
+        let mut b = buffer();
+        b.line(Vec3::new(6., 2., 0.), Vec3::new(1., 2., 0.), 1, false);
+        assert_eq!(
+            vec![(1, 2), (2, 2), (3, 2), (4, 2), (5, 2), (6, 2)],
+            drawn(&b)
+        );
+    }
+
+    #[test]
+    fn line_has_one_pixel_per_step_of_its_long_axis() {
+        // shallow, with one pixel per column
+        let mut b = buffer();
+        b.line(Vec3::new(0., 0., 0.), Vec3::new(7., 3., 0.), 1, false);
+        let pixels = drawn(&b);
+        assert_eq!(8, pixels.len());
+        assert!(pixels.contains(&(0, 0)) && pixels.contains(&(7, 3)));
+        for x in 0..8 {
+            assert_eq!(1, pixels.iter().filter(|p| p.0 == x).count());
+        }
+
+        // steep, with one pixel per row, drawn in either direction
+        let mut b = buffer();
+        b.line(Vec3::new(5., 7., 0.), Vec3::new(3., 0., 0.), 1, false);
+        let pixels = drawn(&b);
+        assert_eq!(8, pixels.len());
+        assert!(pixels.contains(&(3, 0)) && pixels.contains(&(5, 7)));
+        for y in 0..8 {
+            assert_eq!(1, pixels.iter().filter(|p| p.1 == y).count());
+        }
+    }
+
+    #[test]
+    fn line_is_clipped_to_the_canvas() {
+        let mut b = buffer();
+        b.line(Vec3::new(-4., 3., 0.), Vec3::new(12., 3., 0.), 1, false);
+        assert_eq!((0..8).map(|x| (x, 3)).collect::<Vec<_>>(), drawn(&b));
+    }
+
+    #[test]
+    fn line_depth_test() {
+        let mut b = buffer();
+        // a surface at depth 0.5 over the right half
+        for y in 0..8 {
+            for x in 4..8 {
+                b.z_buffer[y * 8 + x] = 0.5;
+            }
+        }
+        // behind the surface
+        b.line(Vec3::new(0., 1., 0.2), Vec3::new(7., 1., 0.2), 1, true);
+        assert_eq!((0..4).map(|x| (x, 1)).collect::<Vec<_>>(), drawn(&b));
+        // on it and in front of it
+        b.line(Vec3::new(0., 2., 0.5), Vec3::new(7., 2., 0.5), 1, true);
+        b.line(Vec3::new(0., 3., 0.9), Vec3::new(7., 3., 0.9), 1, true);
+        assert_eq!(4 + 8 + 8, drawn(&b).len());
+        // without the test, nothing is hidden
+        b.line(Vec3::new(0., 4., 0.2), Vec3::new(7., 4., 0.2), 1, false);
+        assert_eq!(4 + 8 + 8 + 8, drawn(&b).len());
+        // the z buffer is left as it was
+        assert_eq!(0.5, b.z_buffer[3 * 8 + 5]);
+        assert_eq!(f32::NEG_INFINITY, b.z_buffer[3 * 8 + 1]);
+    }
 }
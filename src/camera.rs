@@ -0,0 +1,92 @@
+use glam::{Mat4, Vec3};
+use serde::Deserialize;
+
+/// How the camera maps view space onto the screen
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Projection {
+    /// `fov_y` is the vertical field of view in radians
+    Perspective { fov_y: f32 },
+    /// `height` is the vertical extent of the view volume in world units
+    Orthographic { height: f32 },
+}
+
+/// Fields missing from a scene file are taken from `Camera::default`
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct Camera {
+    pub position: Vec3,
+    pub target: Vec3,
+    pub up: Vec3,
+    pub projection: Projection,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Camera {
+    pub fn new(position: Vec3, target: Vec3, projection: Projection) -> Camera {
+        Camera {
+            position,
+            target,
+            up: Vec3::Y,
+            projection,
+            near: 0.1,
+            far: 100.,
+        }
+    }
+
+    /// world space -> view space
+    pub fn view(&self) -> Mat4 {
+        Mat4::look_at_rh(self.position, self.target, self.up)
+    }
+
+    /// view space -> clip space
+    pub fn projection(&self, aspect_ratio: f32) -> Mat4 {
+        match self.projection {
+            Projection::Perspective { fov_y } => {
+                Mat4::perspective_rh(fov_y, aspect_ratio, self.near, self.far)
+            }
+            Projection::Orthographic { height } => {
+                let half_height = height / 2.;
+                let half_width = half_height * aspect_ratio;
+                Mat4::orthographic_rh(
+                    -half_width,
+                    half_width,
+                    -half_height,
+                    half_height,
+                    self.near,
+                    self.far,
+                )
+            }
+        }
+    }
+
+    /// world space -> clip space, for a `width` by `height` target
+    pub fn view_projection(&self, width: usize, height: usize) -> Mat4 {
+        self.projection(width as f32 / height as f32) * self.view()
+    }
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera::new(
+            Vec3::new(0., 0., 4.),
+            Vec3::ZERO,
+            Projection::Perspective {
+                fov_y: 45f32.to_radians(),
+            },
+        )
+    }
+}
+
+/// Normalized device coordinates -> screen space for a `width` by `height` buffer.
+///
+/// The y axis is flipped so that +y is down the screen. Depth is reversed so
+/// that the near plane maps to 1 and the far plane to 0, which keeps the
+/// greater-is-closer depth test in `Buffer`.
+pub fn viewport(width: usize, height: usize) -> Mat4 {
+    let half_width = width as f32 / 2.;
+    let half_height = height as f32 / 2.;
+    Mat4::from_translation(Vec3::new(half_width, half_height, 1.))
+        * Mat4::from_scale(Vec3::new(half_width, -half_height, -1.))
+}
@@ -0,0 +1,173 @@
+use glam::Vec4;
+
+/// A plane of the view frustum in homogeneous clip space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Plane {
+    Near,
+    Far,
+    Left,
+    Right,
+    Bottom,
+    Top,
+}
+
+impl Plane {
+    pub const FRUSTUM: [Plane; 6] = [
+        Plane::Near,
+        Plane::Far,
+        Plane::Left,
+        Plane::Right,
+        Plane::Bottom,
+        Plane::Top,
+    ];
+
+    /// Positive on the visible side of the plane, zero on it.
+    /// Depth in clip space runs from 0 at the near plane to w at the far plane.
+    fn distance(self, v: Vec4) -> f32 {
+        match self {
+            Plane::Near => v.z,
+            Plane::Far => v.w - v.z,
+            Plane::Left => v.w + v.x,
+            Plane::Right => v.w - v.x,
+            Plane::Bottom => v.w + v.y,
+            Plane::Top => v.w - v.y,
+        }
+    }
+}
+
+/// A vertex that can be clipped. Attributes other than the position are
+/// interpolated in clip space, before the perspective divide.
+pub trait ClipVertex: Copy {
+    fn position(&self) -> Vec4;
+    fn lerp(self, other: Self, t: f32) -> Self;
+}
+
+impl ClipVertex for Vec4 {
+    fn position(&self) -> Vec4 {
+        *self
+    }
+
+    fn lerp(self, other: Self, t: f32) -> Self {
+        Vec4::lerp(self, other, t)
+    }
+}
+
+/// true if `v` is inside every plane of the frustum
+pub fn is_visible(v: Vec4) -> bool {
+    Plane::FRUSTUM.iter().all(|plane| plane.distance(v) >= 0.)
+}
+
+/// Sutherland-Hodgman clipping of a convex polygon against a single plane.
+/// Vertices are kept in order, so the winding of the polygon is preserved.
+pub fn clip_polygon<V: ClipVertex>(polygon: &[V], plane: Plane) -> Vec<V> {
+    let mut clipped = Vec::with_capacity(polygon.len() + 1);
+    let Some(&last) = polygon.last() else {
+        return clipped;
+    };
+
+    let mut previous = last;
+    let mut previous_distance = plane.distance(previous.position());
+    for &current in polygon {
+        let current_distance = plane.distance(current.position());
+        if (previous_distance >= 0.) != (current_distance >= 0.) {
+            let t = previous_distance / (previous_distance - current_distance);
+            clipped.push(previous.lerp(current, t));
+        }
+        if current_distance >= 0. {
+            clipped.push(current);
+        }
+        previous = current;
+        previous_distance = current_distance;
+    }
+    clipped
+}
+
+/// Clip a triangle against the whole view frustum.
+///
+/// Returns the visible part as a convex polygon, which is empty if the
+/// triangle is entirely outside the frustum.
+pub fn clip_triangle<V: ClipVertex>(tri: [V; 3]) -> Vec<V> {
+    if tri.iter().all(|v| is_visible(v.position())) {
+        return tri.to_vec();
+    }
+    Plane::FRUSTUM.iter().fold(tri.to_vec(), |polygon, plane| {
+        clip_polygon(&polygon, *plane)
+    })
+}
+
+/// Clip a line segment against the whole view frustum.
+///
+/// Returns the visible part, or `None` if the segment is entirely outside.
+pub fn clip_line(mut a: Vec4, mut b: Vec4) -> Option<(Vec4, Vec4)> {
+    for plane in Plane::FRUSTUM {
+        let (distance_a, distance_b) = (plane.distance(a), plane.distance(b));
+        match (distance_a >= 0., distance_b >= 0.) {
+            (false, false) => return None,
+            (false, true) => a = a.lerp(b, distance_a / (distance_a - distance_b)),
+            (true, false) => b = a.lerp(b, distance_a / (distance_a - distance_b)),
+            (true, true) => {}
+        }
+    }
+    Some((a, b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inside_is_untouched() {
+        let tri = [
+            Vec4::new(0., 0., 0.5, 1.),
+            Vec4::new(0.5, 0., 0.5, 1.),
+            Vec4::new(0., 0.5, 0.5, 1.),
+        ];
+        assert_eq!(tri.to_vec(), clip_triangle(tri));
+    }
+
+    #[test]
+    fn outside_is_removed() {
+        let tri = [
+            Vec4::new(0., 0., -1., 1.),
+            Vec4::new(0.5, 0., -1., 1.),
+            Vec4::new(0., 0.5, -1., 1.),
+        ];
+        assert!(clip_triangle(tri).is_empty());
+    }
+
+    #[test]
+    fn one_vertex_behind_near_plane() {
+        // one vertex behind the camera turns the triangle into a quad
+        let tri = [
+            Vec4::new(0., 0., -0.5, 1.),
+            Vec4::new(0.5, 0., 0.5, 1.),
+            Vec4::new(0., 0.5, 0.5, 1.),
+        ];
+        let clipped = clip_triangle(tri);
+        assert_eq!(4, clipped.len());
+        assert!(clipped.iter().all(|v| is_visible(*v)));
+    }
+
+    #[test]
+    fn line_through_near_plane() {
+        let (a, b) = clip_line(Vec4::new(0., 0., -1., 1.), Vec4::new(0., 0., 1., 1.)).unwrap();
+        assert_eq!(Vec4::new(0., 0., 0., 1.), a);
+        assert_eq!(Vec4::new(0., 0., 1., 1.), b);
+        assert_eq!(
+            None,
+            clip_line(Vec4::new(0., 0., -1., 1.), Vec4::new(0., 0., -2., 1.))
+        );
+    }
+
+    #[test]
+    fn two_vertices_behind_near_plane() {
+        let tri = [
+            Vec4::new(0., 0., -0.5, 1.),
+            Vec4::new(0.5, 0., -0.5, 1.),
+            Vec4::new(0., 0.5, 0.5, 1.),
+        ];
+        let clipped = clip_triangle(tri);
+        assert_eq!(3, clipped.len());
+        assert!(clipped.iter().all(|v| v.z >= 0.));
+    }
+}
@@ -1,35 +1,283 @@
 use assets_manager::Asset;
-use serde::Deserialize;
-use std::collections::HashMap;
+use glam::Vec3;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+};
 
-use crate::{buffer::Buffer, constants::CLEAR_COLOR, poly::Tri};
+use crate::{buffer::Buffer, poly::Tri, shader::MaterialShader};
 
-#[derive(Clone, Deserialize, Debug, Asset)]
-#[asset_format = "toml"]
+/// Loaded from palette files, see `palette::parse_colors`
+#[derive(Clone, Debug)]
 pub struct Palette {
-    pub colors: [u32; 32],
+    /// 0xRRGGBBAA colors, between `MIN_PALETTE_SIZE` and `MAX_PALETTE_SIZE` of them
+    pub colors: Vec<u32>,
+}
+
+impl Palette {
+    /// Index of the color closest to `color`, which is 0xRRGGBBAA
+    pub fn nearest_to(&self, color: u32) -> u8 {
+        let [r, g, b, _] = color.to_be_bytes();
+        self.nearest(Vec3::new(r as f32, g as f32, b as f32) / 255.)
+    }
+
+    /// Index of the color closest to `rgb`, which has channels from 0 to 1
+    pub fn nearest(&self, rgb: Vec3) -> u8 {
+        // weighted by how sensitive the eye is to each channel
+        const WEIGHTS: Vec3 = Vec3::new(2., 4., 3.);
+        let distance = |color: &u32| {
+            let [r, g, b, _] = color.to_be_bytes();
+            let difference = Vec3::new(r as f32, g as f32, b as f32) / 255. - rgb;
+            (difference * difference).dot(WEIGHTS)
+        };
+        self.colors
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| distance(a).total_cmp(&distance(b)))
+            .map_or(0, |(i, _)| i as u8)
+    }
 }
 
-/// TODO: don't clone/copy this
-#[derive(Deserialize, Debug, Clone, Copy)]
+/// Shade ramps need a dark and a light shade
+pub const MIN_SHADES: usize = 2;
+pub const MAX_SHADES: usize = 32;
+
+/// TODO: don't clone this
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "MaterialDescription")]
 pub struct Material {
-    pub shades: [u8; 9],
+    /// palette indices from darkest to brightest, `MIN_SHADES` to `MAX_SHADES` of them
+    pub shades: Vec<u8>,
+    /// how light levels map to shades, or the shader's own curve if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub curve: Option<LightCurve>,
+    /// overrides the shader of the render for this material
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shader: Option<MaterialShader>,
+}
+
+impl Material {
+    /// Drawn in place of materials that don't fit the palette, in the color
+    /// closest to magenta at every light level
+    pub fn error(palette: &Palette) -> Material {
+        let magenta = palette.nearest(Vec3::new(1., 0., 1.));
+        Material {
+            shades: vec![magenta; MIN_SHADES],
+            curve: None,
+            shader: None,
+        }
+    }
+
+    /// Where light level `light` falls along the shade ramp, from 0 to 1,
+    /// with `default` if the material has no curve of its own
+    pub fn shade_position(&self, light: f32, default: &LightCurve) -> f32 {
+        self.curve.as_ref().unwrap_or(default).position(light)
+    }
+
+    /// Index into `shades` for light level `light`, see `shade_position`
+    pub fn shade_index(&self, light: f32, default: &LightCurve) -> usize {
+        let count = self.shades.len();
+        let index = match self.curve.as_ref().unwrap_or(default) {
+            LightCurve::Exponential if count == 9 => {
+                2.0f32.powf(3.0 * light.clamp(0., 1.)).floor() as usize
+            }
+            curve => (curve.position(light) * count as f32) as usize,
+        };
+        index.min(count - 1)
+    }
+}
+
+/// A material as written in toml, checked before it becomes a `Material`
+#[derive(Deserialize)]
+struct MaterialDescription {
+    shades: Vec<u8>,
+    #[serde(default)]
+    curve: Option<LightCurve>,
+    #[serde(default)]
+    shader: Option<MaterialShader>,
 }
 
-#[derive(Deserialize, Debug, Clone, Asset)]
+impl TryFrom<MaterialDescription> for Material {
+    type Error = String;
+
+    fn try_from(value: MaterialDescription) -> Result<Self, Self::Error> {
+        let count = value.shades.len();
+        if !(MIN_SHADES..=MAX_SHADES).contains(&count) {
+            return Err(format!(
+                "{count} shades, materials need {MIN_SHADES} to {MAX_SHADES}"
+            ));
+        }
+        if let Some(LightCurve::Custom(breakpoints)) = &value.curve {
+            if breakpoints.len() != count - 1 {
+                return Err(format!(
+                    "{} breakpoints for {count} shades, the curve needs {}",
+                    breakpoints.len(),
+                    count - 1
+                ));
+            }
+            let in_range = breakpoints.iter().all(|b| (0. ..=1.).contains(b));
+            if !in_range || breakpoints.windows(2).any(|pair| pair[0] > pair[1]) {
+                return Err("breakpoints need to go up from 0 to 1".to_string());
+            }
+        }
+        Ok(Material {
+            shades: value.shades,
+            curve: value.curve,
+            shader: value.shader,
+        })
+    }
+}
+
+/// How light levels from 0 to 1 are spread over a material's shades, e.g.
+/// `curve = "linear"` or `curve = { custom = [0.3, 0.8] }` in `materials.toml`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LightCurve {
+    /// every shade gets an equal part of the light range
+    Linear,
+    /// `2^(3 * light)`, so bright shades get less of the light range.
+    /// 9 shade ramps get shade `floor(2^(3 * light))` like before materials
+    /// had curves, which leaves out their darkest shade.
+    Exponential,
+    /// the light levels at which each shade after the first starts, going up.
+    /// There is one fewer than there are shades.
+    Custom(Vec<f32>),
+}
+
+impl LightCurve {
+    /// Where `light` falls along a shade ramp, from 0 to 1
+    pub fn position(&self, light: f32) -> f32 {
+        let light = light.clamp(0., 1.);
+        match self {
+            LightCurve::Linear => light,
+            LightCurve::Exponential => (2.0f32.powf(3.0 * light) - 1.) / 7.,
+            LightCurve::Custom(breakpoints) => {
+                // linear within each shade's range, so shaders can dither
+                // between neighbouring shades
+                let shade = breakpoints.iter().filter(|&&b| b <= light).count();
+                let start = shade.checked_sub(1).map_or(0., |i| breakpoints[i]);
+                let end = breakpoints.get(shade).copied().unwrap_or(1.);
+                let within = if end > start {
+                    (light - start) / (end - start)
+                } else {
+                    0.
+                };
+                ((shade as f32 + within) / (breakpoints.len() + 1) as f32).min(1.)
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default, Asset)]
 #[asset_format = "toml"]
 #[serde(transparent)]
 pub struct NamedMaterials(pub HashMap<String, Material>);
 
-#[derive(Debug)]
-pub struct Materials(pub Vec<Material>);
+impl NamedMaterials {
+    /// The names in the order of the converted `Materials`
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.0.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// The first shade of each material that isn't a color of `palette`,
+    /// sorted by material name
+    pub fn validate(&self, palette: &Palette) -> Vec<MaterialError> {
+        let mut errors: Vec<MaterialError> = self
+            .0
+            .iter()
+            .filter_map(|(name, material)| {
+                let shade = material
+                    .shades
+                    .iter()
+                    .position(|&index| index as usize >= palette.colors.len())?;
+                Some(MaterialError {
+                    material: name.clone(),
+                    shade,
+                    index: material.shades[shade],
+                    palette_size: palette.colors.len(),
+                })
+            })
+            .collect();
+        errors.sort_by(|a, b| a.material.cmp(&b.material));
+        errors
+    }
+
+    /// Logs the errors of `validate` and swaps the broken materials for
+    /// `Material::error`, so they can still be drawn
+    pub fn checked(mut self, palette: &Palette) -> Self {
+        for error in self.validate(palette) {
+            log::error!("{error}");
+            self.0.insert(error.material, Material::error(palette));
+        }
+        self
+    }
+}
+
+/// A material shade that refers to no color of the palette
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaterialError {
+    pub material: String,
+    /// index into the material's shades
+    pub shade: usize,
+    /// the palette index of that shade
+    pub index: u8,
+    pub palette_size: usize,
+}
+
+impl fmt::Display for MaterialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "material {}: shade {} is color {}, but the palette only has {} colors",
+            self.material, self.shade, self.index, self.palette_size
+        )
+    }
+}
+
+impl std::error::Error for MaterialError {}
+
+/// The materials of a mesh, which triangles refer to by index
+#[derive(Debug, Clone, Default)]
+pub struct Materials {
+    pub materials: Vec<Material>,
+    /// the name of each of `materials`
+    pub names: Vec<String>,
+    /// id of the toml file the materials were loaded from, which
+    /// `export::save_materials` writes them back to
+    pub source: Option<String>,
+    /// indices of the materials changed by `set_shade` since loading, the
+    /// only ones that get saved
+    pub edited: BTreeSet<usize>,
+}
+
+impl Materials {
+    /// Materials called `names`, that weren't loaded from a toml file
+    pub fn new(materials: Vec<Material>, names: Vec<String>) -> Self {
+        Materials {
+            materials,
+            names,
+            source: None,
+            edited: BTreeSet::new(),
+        }
+    }
+
+    /// Sets shade `shade` of material `material` to palette index `color`
+    pub fn set_shade(&mut self, material: usize, shade: usize, color: u8) {
+        self.materials[material].shades[shade] = color;
+        self.edited.insert(material);
+    }
+}
 
 impl From<NamedMaterials> for Materials {
     fn from(value: NamedMaterials) -> Self {
         let mut unsorted_materials = value.0.clone().into_iter().collect::<Vec<_>>();
         // Using a manual sort_by_key here, to satisfy lifetime weirdness
         unsorted_materials.sort_by(|x, y| x.0.cmp(&(y.0)));
-        Materials(unsorted_materials.into_iter().map(|(_k, v)| v).collect())
+        let (names, materials) = unsorted_materials.into_iter().unzip();
+        Materials::new(materials, names)
     }
 }
 
@@ -86,61 +334,25 @@ pub fn grayscale(value: f32) -> Color {
     }
 }
 
-/// Convert a float between 0.0 and 1.0 and a color to a lit color
-pub fn lit_color(value: f32, base_color: Material) -> u8 {
-    let scaled = 2.0f32.powf(3.0 * value.clamp(0., 1.));
-    let index = scaled.floor() as usize;
-    let shades = base_color.shades;
-    shades[index]
-}
-
-pub fn flat_lit_shader(_x: usize, _y: usize, material: Option<&Material>, light: f32) -> u8 {
-    material
-        .map(|m| lit_color(light, *m))
-        .unwrap_or(CLEAR_COLOR)
-}
-
-fn color_index(v: f32, num_colors: usize) -> usize {
-    ((v * num_colors as f32).floor() as usize).clamp(0, num_colors - 1)
-}
-
-fn color_rounded_index(v: f32, num_colors: usize) -> usize {
-    // in index units, the transition point closest to v
-    (v * num_colors as f32).round() as usize
+/// Convert a float between 0.0 and 1.0 and a color to a lit color, along the
+/// material's curve or else `LightCurve::Exponential`
+pub fn lit_color(value: f32, base_color: &Material) -> u8 {
+    base_color.shades[base_color.shade_index(value, &LightCurve::Exponential)]
 }
 
-fn closest_transition(v: f32, num_colors: usize) -> f32 {
-    // in light units, the transition point closest to v
-    (color_rounded_index(v, num_colors) as f32 - 1.) / num_colors as f32
-}
-
-fn transition_distance(v: f32, num_colors: usize) -> f32 {
-    (v - closest_transition(v, num_colors)).abs()
-}
-
-const DITHER_RATIO: f32 = 0.5; // should go between 0 and 1
-pub fn dither_mask_shader(x: usize, y: usize, material: Option<&Material>, light: f32) -> u8 {
-    material
-        .map(|m| {
-            // TODO which light scaling should we do?
-            // let scaled = 2.0f32.powf(3.0 * light.clamp(0., 1.)) / 9.;
-            let num_shades = m.shades.len();
-            // let num_shades = 3;
-            let scaled = light.clamp(0., 1.);
-            let c2 = color_rounded_index(closest_transition(scaled, num_shades), num_shades);
-            let c1 = (c2.saturating_sub(1)).clamp(0, num_shades - 1);
-            let transition_width = 1. / num_shades as f32;
-            if transition_distance(scaled, num_shades) <= transition_width * (DITHER_RATIO + 0.5) {
-                if (x ^ y) % 2 == 0 {
-                    m.shades[c2]
-                } else {
-                    m.shades[c1]
-                }
-            } else {
-                m.shades[color_index(scaled, num_shades)]
-            }
-        })
-        .unwrap_or(CLEAR_COLOR)
+/// Light a texel by treating the material's shade ramp as a color map.
+///
+/// A texel is taken to be its color at full light. If it appears in the ramp,
+/// it moves down the ramp by as many steps as `lit_color` would from the
+/// brightest shade. Texels that aren't in the ramp are unaffected by light.
+pub fn lit_texel(texel: u8, light: f32, base_color: &Material) -> u8 {
+    let index = base_color.shade_index(light, &LightCurve::Exponential);
+    let darkening = (base_color.shades.len() - 1) - index;
+    let shades = &base_color.shades;
+    match shades.iter().rposition(|&shade| shade == texel) {
+        Some(position) => shades[position.saturating_sub(darkening)],
+        None => texel,
+    }
 }
 
 pub fn lit_color_old(value: f32, base_color: Color) -> Color {
@@ -185,3 +397,119 @@ pub fn lit_color_old(value: f32, base_color: Color) -> Color {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn material(toml: &str) -> Result<Material, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    #[test]
+    fn ramps_of_any_length() {
+        let cel = material("shades = [1, 2, 3]\ncurve = \"linear\"").unwrap();
+        assert_eq!(1, lit_color(0.2, &cel));
+        assert_eq!(2, lit_color(0.5, &cel));
+        assert_eq!(3, lit_color(1.0, &cel));
+
+        let long = material(&format!("shades = {:?}", (0..16).collect::<Vec<u8>>())).unwrap();
+        // exponential by default, so half light is less than half way up
+        assert_eq!(0, lit_color(0., &long));
+        assert_eq!(15, lit_color(1., &long));
+        assert!(lit_color(0.5, &long) < 8);
+        let linear = material(&format!(
+            "shades = {:?}\ncurve = \"linear\"",
+            (0..16).collect::<Vec<u8>>()
+        ))
+        .unwrap();
+        assert_eq!(0, lit_color(0., &linear));
+
+        assert!(material("shades = [1]").is_err());
+        assert!(material(&format!("shades = {:?}", [0; 33])).is_err());
+    }
+
+    #[test]
+    fn texels_darken_along_the_ramp() {
+        let m = material(&format!("shades = {:?}", (10..19).collect::<Vec<u8>>())).unwrap();
+        // the brightest shade is lit like the material itself
+        for light in [0., 0.3, 0.6, 1.] {
+            assert_eq!(lit_color(light, &m), lit_texel(18, light, &m));
+        }
+        // other shades move down by as many steps, stopping at the darkest
+        let steps = 18 - lit_color(0.6, &m);
+        assert!(steps > 0);
+        assert_eq!(17 - steps, lit_texel(17, 0.6, &m));
+        assert_eq!(10, lit_texel(11, 0., &m));
+        assert_eq!(14, lit_texel(14, 1., &m));
+        // colors that aren't in the ramp are unlit
+        assert_eq!(99, lit_texel(99, 0., &m));
+    }
+
+    #[test]
+    fn short_ramps_use_every_shade() {
+        let two = material("shades = [1, 2]").unwrap();
+        assert_eq!(1, lit_color(0., &two));
+        assert_eq!(1, lit_color(0.5, &two));
+        assert_eq!(2, lit_color(0.9, &two));
+        assert_eq!(2, lit_color(1., &two));
+
+        let cel = material("shades = [1, 2, 3]").unwrap();
+        assert_eq!(1, lit_color(0., &cel));
+        assert_eq!(2, lit_color(0.6, &cel));
+        assert_eq!(3, lit_color(0.9, &cel));
+        assert_eq!(3, lit_color(1., &cel));
+    }
+
+    #[test]
+    fn nine_shades_keep_their_light_levels() {
+        let m = material(&format!("shades = {:?}", (10..19).collect::<Vec<u8>>())).unwrap();
+        for step in 0..=1000 {
+            let light = step as f32 / 1000.;
+            // how lit_color picked shades before materials had curves
+            let old = 10 + 2.0f32.powf(3.0 * light).floor() as u8;
+            assert_eq!(old, lit_color(light, &m), "light {light}");
+        }
+        assert_eq!(11, lit_color(0., &m));
+        assert_eq!(16, lit_color(0.9, &m));
+        assert_eq!(18, lit_color(1., &m));
+    }
+
+    #[test]
+    fn custom_curve() {
+        let m = material("shades = [1, 2, 3]\ncurve = { custom = [0.1, 0.9] }").unwrap();
+        assert_eq!(1, lit_color(0.05, &m));
+        assert_eq!(2, lit_color(0.1, &m));
+        assert_eq!(2, lit_color(0.85, &m));
+        assert_eq!(3, lit_color(0.95, &m));
+        // halfway through the middle shade's range
+        assert!((m.shade_position(0.5, &LightCurve::Linear) - 0.5).abs() < 1e-6);
+
+        assert!(material("shades = [1, 2, 3]\ncurve = { custom = [0.5] }").is_err());
+        assert!(material("shades = [1, 2, 3]\ncurve = { custom = [0.9, 0.1] }").is_err());
+    }
+
+    #[test]
+    fn materials_outside_palette() {
+        let palette = Palette {
+            colors: vec![0x000000FF, 0xFF00FFFF, 0xFFFFFFFF],
+        };
+        let materials: NamedMaterials = toml::from_str(
+            "
+            fine = { shades = [0, 1, 2] }
+            broken = { shades = [0, 2, 3, 4] }
+            ",
+        )
+        .unwrap();
+        let errors = materials.validate(&palette);
+        assert_eq!(1, errors.len());
+        assert_eq!(
+            "material broken: shade 2 is color 3, but the palette only has 3 colors",
+            errors[0].to_string()
+        );
+
+        let checked = materials.checked(&palette);
+        assert_eq!(vec![1, 1], checked.0["broken"].shades);
+        assert_eq!(vec![0, 1, 2], checked.0["fine"].shades);
+    }
+}
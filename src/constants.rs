@@ -1,6 +1,9 @@
 pub const WIDTH: usize = 80;
 pub const HEIGHT: usize = 100;
 
-/// u8 value, because that is the biggest that will fit into palette_pixels
-pub const COLOR_DEPTH: u8 = 32;
-pub const CLEAR_COLOR: u8 = 21;
+/// Palettes need at least two colors to draw anything
+pub const MIN_PALETTE_SIZE: usize = 2;
+/// Canvas pixels and material shades are u8 palette indices
+pub const MAX_PALETTE_SIZE: usize = u8::MAX as usize + 1;
+/// 0xRRGGBBAA background color, drawn with the nearest palette color
+pub const CLEAR_COLOR: u32 = 0x17735FFF;
@@ -0,0 +1,330 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use toml_edit::{value, Array, DocumentMut, Item, Value};
+
+use crate::{
+    color::{Materials, NamedMaterials, Palette},
+    geo::Mesh,
+};
+
+/// Writes `mesh` as an .obj file, with the `.mtl` file next to it
+pub fn save(path: &Path, mesh: &Mesh, palette: &Palette) -> io::Result<()> {
+    let mtl_path = path.with_extension("mtl");
+    let mtllib = mtl_path.file_name().and_then(|name| name.to_str());
+
+    let mut obj = BufWriter::new(File::create(path)?);
+    write_obj(&mut obj, mesh, mtllib)?;
+    obj.flush()?;
+
+    let mut mtl = BufWriter::new(File::create(&mtl_path)?);
+    write_mtl(&mut mtl, mesh, palette)?;
+    mtl.flush()
+}
+
+/// Writes vertices, texture coordinates, normals, lines and faces, with a
+/// `usemtl` whenever the material changes. Triangles keep their order and
+/// every corner refers to its normal, so `obj::parse` reads back the same mesh.
+pub fn write_obj(out: &mut impl Write, mesh: &Mesh, mtllib: Option<&str>) -> io::Result<()> {
+    if let Some(mtllib) = mtllib {
+        writeln!(out, "mtllib {mtllib}")?;
+    }
+    for v in &mesh.vertices {
+        writeln!(out, "v {} {} {}", v.x, v.y, v.z)?;
+    }
+    for uv in &mesh.uvs {
+        writeln!(out, "vt {} {}", uv.x, uv.y)?;
+    }
+    for n in &mesh.normals {
+        writeln!(out, "vn {} {} {}", n.x, n.y, n.z)?;
+    }
+    for (a, b) in &mesh.lines {
+        writeln!(out, "l {} {}", a + 1, b + 1)?;
+    }
+
+    let mut current_material = None;
+    for triangle in &mesh.triangles {
+        if current_material != Some(triangle.material_index) {
+            let name = mesh
+                .materials
+                .names
+                .get(triangle.material_index)
+                .ok_or_else(|| {
+                    io::Error::other(format!("No name for material {}", triangle.material_index))
+                })?;
+            writeln!(out, "usemtl {name}")?;
+            current_material = Some(triangle.material_index);
+        }
+        let (t1, t2, t3) = triangle.index;
+        let (n1, n2, n3) = triangle.normal_index;
+        let corners = match triangle.uv_index {
+            Some((u1, u2, u3)) => [(t1, u1, n1), (t2, u2, n2), (t3, u3, n3)]
+                .map(|(t, u, n)| format!("{}/{}/{}", t + 1, u + 1, n + 1)),
+            None => [(t1, n1), (t2, n2), (t3, n3)].map(|(t, n)| format!("{}//{}", t + 1, n + 1)),
+        };
+        writeln!(out, "f {}", corners.join(" "))?;
+    }
+    Ok(())
+}
+
+/// Writes each material with the brightest color of its shade ramp as the
+/// diffuse color
+pub fn write_mtl(out: &mut impl Write, mesh: &Mesh, palette: &Palette) -> io::Result<()> {
+    let materials = &mesh.materials;
+    for (name, material) in materials.names.iter().zip(&materials.materials) {
+        let brightest = material.shades[material.shades.len() - 1];
+        let [r, g, b, _] = palette.colors[brightest as usize].to_be_bytes();
+        writeln!(out, "newmtl {name}")?;
+        writeln!(
+            out,
+            "Kd {:.4} {:.4} {:.4}",
+            r as f32 / 255.,
+            g as f32 / 255.,
+            b as f32 / 255.
+        )?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Writes the edited ones of `materials` to the toml file at `path`, by name.
+/// Only the `shades` of materials already in the file are replaced, so its
+/// comments, order and other materials are kept. Materials missing from it
+/// are added at the end.
+///
+/// Materials that don't fit `palette` in the file were drawn as
+/// `Material::error`, so edits of them are not saved over the original.
+/// Returns the names of those.
+pub fn save_materials(
+    path: &Path,
+    materials: &Materials,
+    palette: &Palette,
+) -> io::Result<Vec<String>> {
+    let toml_string = match fs::read_to_string(path) {
+        Ok(toml_string) => toml_string,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let on_disk: NamedMaterials = toml::from_str(&toml_string).map_err(io::Error::other)?;
+    let broken: Vec<String> = on_disk
+        .validate(palette)
+        .into_iter()
+        .map(|error| error.material)
+        .collect();
+
+    let mut document: DocumentMut = toml_string.parse().map_err(io::Error::other)?;
+    let mut skipped = Vec::new();
+    for &index in &materials.edited {
+        let name = &materials.names[index];
+        let material = &materials.materials[index];
+        if broken.contains(name) {
+            skipped.push(name.clone());
+            continue;
+        }
+        match document.get_mut(name).and_then(Item::as_table_like_mut) {
+            Some(table) => {
+                let mut shades: Array = material.shades.iter().map(|&s| s as i64).collect();
+                shades.fmt();
+                // replaced in place, so comments on the key stay where they are
+                match table.get_mut("shades").and_then(Item::as_value_mut) {
+                    Some(old) => {
+                        let decor = old.decor().clone();
+                        *old = Value::Array(shades);
+                        *old.decor_mut() = decor;
+                    }
+                    None => {
+                        table.insert("shades", value(shades));
+                    }
+                }
+            }
+            None => {
+                let new: DocumentMut = toml::to_string(material)
+                    .map_err(io::Error::other)?
+                    .parse()
+                    .map_err(io::Error::other)?;
+                document.insert(name, Item::Table(new.as_table().clone()));
+            }
+        }
+    }
+    if skipped.len() < materials.edited.len() {
+        fs::write(path, document.to_string())?;
+    }
+    Ok(skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use glam::Vec3;
+
+    use super::*;
+    use crate::{color::Material, mtl, obj};
+
+    fn materials() -> NamedMaterials {
+        let material = |shade| Material {
+            shades: vec![shade; 9],
+            curve: None,
+            shader: None,
+        };
+        NamedMaterials(HashMap::from([
+            ("red".to_string(), material(1)),
+            ("blue".to_string(), material(2)),
+        ]))
+    }
+
+    #[test]
+    fn obj_round_trip() {
+        let source = "
+            v 0 0 0
+            v 1 0 0
+            v 1 1 0
+            v 0 1 0.5
+            vt 0 0
+            vt 1 0
+            vt 1 1
+            vn 0 0 1
+            l 1 3
+            usemtl red
+            f 1/1/1 2/2/1 3/3/1
+            usemtl blue
+            f 1 3 4
+            usemtl red
+            f 2 3 4
+            ";
+        let mesh = obj::parse_str(source, materials()).unwrap();
+        let mut written = Vec::new();
+        write_obj(&mut written, &mesh, None).unwrap();
+        let reparsed = obj::parse_str(std::str::from_utf8(&written).unwrap(), materials()).unwrap();
+
+        assert_eq!(mesh.vertices, reparsed.vertices);
+        assert_eq!(mesh.uvs, reparsed.uvs);
+        assert_eq!(mesh.lines, reparsed.lines);
+        assert_eq!(mesh.normals.len(), reparsed.normals.len());
+        for (a, b) in mesh.normals.iter().zip(&reparsed.normals) {
+            assert!(a.abs_diff_eq(*b, 1e-6));
+        }
+        assert_eq!(mesh.triangles.len(), reparsed.triangles.len());
+        for (a, b) in mesh.triangles.iter().zip(&reparsed.triangles) {
+            assert_eq!(a.index, b.index);
+            assert_eq!(a.normal_index, b.normal_index);
+            assert_eq!(a.uv_index, b.uv_index);
+            assert_eq!(a.material_index, b.material_index);
+        }
+    }
+
+    #[test]
+    fn mtl_round_trip() {
+        let mut colors = [0x000000FF; 32];
+        colors[1] = 0xFF0000FF;
+        colors[2] = 0x0000FFFF;
+        let palette = Palette {
+            colors: colors.to_vec(),
+        };
+        let mesh = obj::parse_str("v 0 0 0 \n v 1 0 0 \n v 0 1 0 \n f 1 2 3", materials()).unwrap();
+
+        let mut written = Vec::new();
+        write_mtl(&mut written, &mesh, &palette).unwrap();
+        let mtl = mtl::parse(std::str::from_utf8(&written).unwrap()).unwrap();
+        assert_eq!(Vec3::X, mtl.0["red"].diffuse);
+        assert_eq!(Vec3::Z, mtl.0["blue"].diffuse);
+        // ramps generated from the written colors give back the brightest shade
+        let generated = mtl.to_materials(&palette);
+        assert_eq!(1, generated.0["red"].shades[8]);
+    }
+
+    /// A file for the test `name` that no other test run writes to
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let file = format!("pyx_rusterizer_{}_{name}.toml", std::process::id());
+        std::env::temp_dir().join(file)
+    }
+
+    fn palette() -> Palette {
+        Palette {
+            colors: vec![0x000000FF; 8],
+        }
+    }
+
+    #[test]
+    fn save_materials_keeps_names() {
+        let path = temp_path("save_materials_keeps_names");
+        fs::write(&path, "[green]\nshades = [3, 3]\n[red]\nshades = [0, 0]\n").unwrap();
+
+        let mut edited = Materials::from(materials());
+        assert_eq!(vec!["blue", "red"], edited.names);
+        edited.set_shade(1, 0, 5);
+        let skipped = save_materials(&path, &edited, &palette()).unwrap();
+        let saved: NamedMaterials = toml::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(skipped.is_empty());
+        // only edited materials are written, others in the file are kept
+        assert_eq!(vec!["green", "red"], saved.names());
+        assert_eq!(vec![3, 3], saved.0["green"].shades);
+        assert_eq!(5, saved.0["red"].shades[0]);
+        assert_eq!(1, saved.0["red"].shades[1]);
+    }
+
+    #[test]
+    fn save_materials_keeps_broken_materials() {
+        let path = temp_path("save_materials_keeps_broken_materials");
+        let original = "[broken]\nshades = [1, 40]\n[green]\nshades = [3, 3]\n";
+        fs::write(&path, original).unwrap();
+        let loaded: NamedMaterials = toml::from_str(original).unwrap();
+        let mut materials = Materials::from(loaded.checked(&palette()));
+        assert_eq!(
+            Material::error(&palette()).shades,
+            materials.materials[0].shades
+        );
+
+        // painting over the stand-in doesn't replace the original
+        materials.set_shade(0, 0, 2);
+        let skipped = save_materials(&path, &materials, &palette()).unwrap();
+        assert_eq!(vec!["broken"], skipped);
+        assert_eq!(original, fs::read_to_string(&path).unwrap());
+
+        materials.set_shade(1, 1, 4);
+        let skipped = save_materials(&path, &materials, &palette()).unwrap();
+        let saved: NamedMaterials = toml::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(vec!["broken"], skipped);
+        assert_eq!(vec![1, 40], saved.0["broken"].shades);
+        assert_eq!(vec![3, 4], saved.0["green"].shades);
+    }
+
+    #[test]
+    fn save_materials_keeps_formatting() {
+        let path = temp_path("save_materials_keeps_formatting");
+        let original = "\
+[mat4]
+# Cyan
+shades = [3, 3]
+curve = { custom = [0.3] }
+
+[mat21]
+# White
+shades = [4, 5, 6]
+";
+        fs::write(&path, original).unwrap();
+        let loaded: NamedMaterials = toml::from_str(original).unwrap();
+        let mut materials = Materials::from(loaded);
+        materials.materials.push(Material {
+            shades: vec![1, 2],
+            curve: None,
+            shader: None,
+        });
+        materials.names.push("blue".to_string());
+        assert_eq!(vec!["mat21", "mat4", "blue"], materials.names);
+
+        materials.set_shade(0, 1, 7);
+        materials.set_shade(2, 0, 0);
+        save_materials(&path, &materials, &palette()).unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let expected = original.replace("[4, 5, 6]", "[4, 7, 6]") + "\n[blue]\nshades = [0, 2]\n";
+        assert_eq!(expected, saved);
+    }
+}
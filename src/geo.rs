@@ -1,67 +1,497 @@
-use glam::{Affine3A, Vec3};
+use std::{collections::HashSet, ops::AddAssign};
+
+use glam::{Affine3A, Mat4, Vec2, Vec3, Vec4, Vec4Swizzles};
 
 use crate::{
-    buffer::Buffer,
+    buffer::{Buffer, TriangleId},
+    camera::{self, Camera},
+    clip::{self, ClipVertex},
     color::Materials,
-    poly::{self, Tri},
+    light::{self, Light},
+    poly::{self, Tri, Varyings},
+    texture::Texture,
 };
 
 pub trait Shape {
-    fn render(&self, buffer: &mut Buffer, transform: Affine3A);
+    /// `mesh_id` is recorded in the G-buffer for every covered pixel
+    fn render(
+        &self,
+        buffer: &mut Buffer,
+        mesh_id: usize,
+        transform: Affine3A,
+        camera: &Camera,
+        lights: &[Light],
+    ) -> RenderStats;
+
+    /// Draws straight onto the canvas, after `Buffer::finalize_render`
+    fn render_overlay(&self, buffer: &mut Buffer, transform: Affine3A, camera: &Camera);
+}
+
+/// Settings for drawing triangle edges
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Wireframe {
+    /// palette index of the lines
+    pub color: u8,
+    /// hide edges behind surfaces in the z buffer. In `RenderMode::Wireframe`
+    /// the mesh's own faces are drawn into the z buffer, in the clear color,
+    /// so they hide its back edges and whatever else is behind them.
+    pub depth_test: bool,
+}
+
+/// How a mesh shows up on screen
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RenderMode {
+    #[default]
+    Shaded,
+    /// only the edges of the triangles
+    Wireframe(Wireframe),
+    /// edges drawn over the shaded triangles
+    ShadedWireframe(Wireframe),
+}
+
+/// Which faces to skip when rendering
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CullMode {
+    None,
+    #[default]
+    Back,
+    Front,
+}
+
+/// Vertex order of front faces, as seen by the viewer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Winding {
+    #[default]
+    CounterClockwise,
+    Clockwise,
+}
+
+/// Triangle counts for a rendered frame
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RenderStats {
+    /// triangles handed to the rasterizer
+    pub drawn: usize,
+    /// triangles skipped by the cull mode
+    pub culled: usize,
+    /// triangles entirely outside the view frustum
+    pub outside: usize,
+}
+
+impl AddAssign for RenderStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.drawn += rhs.drawn;
+        self.culled += rhs.culled;
+        self.outside += rhs.outside;
+    }
 }
 
 type Vertex = Vec3;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct IndexedTriangle {
     /// indices that correspond to vertiecs in Mesh
     pub index: (usize, usize, usize),
+    /// indices that correspond to normals in Mesh
+    pub normal_index: (usize, usize, usize),
+    /// indices that correspond to uvs in Mesh, if the triangle is textured
+    pub uv_index: Option<(usize, usize, usize)>,
     pub material_index: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Mesh {
     pub vertices: Vec<Vertex>,
+    /// unit vertex normals, used for smooth shading
+    pub normals: Vec<Vec3>,
+    /// texture coordinates
+    pub uvs: Vec<Vec2>,
     pub triangles: Vec<IndexedTriangle>,
+    /// edges that are not part of a triangle, as indices into `vertices`.
+    /// Only drawn in the wireframe render modes.
+    pub lines: Vec<(usize, usize)>,
     pub materials: Materials,
+    pub texture: Option<Texture>,
+    pub cull_mode: CullMode,
+    pub winding: Winding,
+    pub render_mode: RenderMode,
+}
+
+impl Mesh {
+    /// Whether a triangle, or the polygon clipping made of it, with screen
+    /// space vertices `polygon` should be skipped
+    fn is_culled(&self, polygon: &[Vertex]) -> bool {
+        // screen space has +y pointing down, which flips the sign of the area
+        let counter_clockwise = signed_area(polygon) < 0.;
+        let front_facing = match self.winding {
+            Winding::CounterClockwise => counter_clockwise,
+            Winding::Clockwise => !counter_clockwise,
+        };
+        match self.cull_mode {
+            CullMode::None => false,
+            CullMode::Back => !front_facing,
+            CullMode::Front => front_facing,
+        }
+    }
+}
+
+/// Twice the area of `polygon` in x and y, positive if its vertices go
+/// counter clockwise with y pointing up. Summed over the whole polygon, since
+/// clipping can leave vertices that coincide or lie on a line.
+fn signed_area(polygon: &[Vertex]) -> f32 {
+    polygon
+        .iter()
+        .zip(polygon.iter().cycle().skip(1))
+        .map(|(a, b)| a.truncate().perp_dot(b.truncate()))
+        .sum()
+}
+
+/// Averages the face normals around each vertex, weighted by face area.
+/// `faces` index into `vertices`; the result has one normal per vertex.
+pub fn vertex_normals(vertices: &[Vertex], faces: &[(usize, usize, usize)]) -> Vec<Vec3> {
+    let mut normals = vec![Vec3::ZERO; vertices.len()];
+    for &(t1, t2, t3) in faces {
+        // the cross product's length is twice the face area
+        let face_normal = (vertices[t2] - vertices[t1]).cross(vertices[t3] - vertices[t1]);
+        normals[t1] += face_normal;
+        normals[t2] += face_normal;
+        normals[t3] += face_normal;
+    }
+    normals.into_iter().map(Vec3::normalize_or_zero).collect()
+}
+
+/// A vertex in clip space along with the attributes interpolated across its triangle
+#[derive(Debug, Clone, Copy)]
+struct ClippedVertex {
+    position: Vec4,
+    varyings: Varyings,
+}
+
+impl ClipVertex for ClippedVertex {
+    fn position(&self) -> Vec4 {
+        self.position
+    }
+
+    fn lerp(self, other: Self, t: f32) -> Self {
+        ClippedVertex {
+            position: self.position.lerp(other.position, t),
+            varyings: self.varyings * (1. - t) + other.varyings * t,
+        }
+    }
 }
 
 impl Shape for Mesh {
-    fn render(&self, buffer: &mut Buffer, transform: Affine3A) {
-        let transformed_verts: Vec<Vertex> = (&self.vertices)
-            .into_iter()
+    fn render(
+        &self,
+        buffer: &mut Buffer,
+        mesh_id: usize,
+        transform: Affine3A,
+        camera: &Camera,
+        lights: &[Light],
+    ) -> RenderStats {
+        let mut stats = RenderStats::default();
+        // a wireframe only needs its faces for depth testing its edges
+        let depth_only = match self.render_mode {
+            RenderMode::Wireframe(wireframe) if !wireframe.depth_test => return stats,
+            RenderMode::Wireframe(_) => true,
+            RenderMode::Shaded | RenderMode::ShadedWireframe(_) => false,
+        };
+
+        let world_verts: Vec<Vertex> = self
+            .vertices
+            .iter()
             .map(|v| transform.transform_point3(*v))
             .collect();
 
+        let normal_matrix = transform.matrix3.inverse().transpose();
+        let world_normals: Vec<Vec3> = self
+            .normals
+            .iter()
+            .map(|n| (normal_matrix * *n).normalize_or_zero())
+            .collect();
+
+        let clip_from_world = camera.view_projection(buffer.width(), buffer.height());
+        let screen_from_ndc = camera::viewport(buffer.width(), buffer.height());
+        let clip_verts: Vec<Vec4> = world_verts
+            .iter()
+            .map(|v| clip_from_world * v.extend(1.))
+            .collect();
+
+        buffer.depth_only = depth_only;
         for (i, triangle) in self.triangles.iter().enumerate() {
             let (t1, t2, t3) = triangle.index;
-            let vert_tri = Tri::new(
-                transformed_verts[t1],
-                transformed_verts[t2],
-                transformed_verts[t3],
-                self.materials.0[triangle.material_index],
-                i,
-            );
-            poly::draw_tri(buffer, &vert_tri);
+            let (n1, n2, n3) = triangle.normal_index;
+            let (uv1, uv2, uv3) = match triangle.uv_index {
+                Some((u1, u2, u3)) => (self.uvs[u1], self.uvs[u2], self.uvs[u3]),
+                None => (Vec2::ZERO, Vec2::ZERO, Vec2::ZERO),
+            };
+            let corner = |t: usize, n: usize, uv: Vec2| ClippedVertex {
+                position: clip_verts[t],
+                varyings: Varyings {
+                    light: light::illumination(lights, world_verts[t], world_normals[n]),
+                    uv,
+                    normal: world_normals[n],
+                    position: world_verts[t],
+                },
+            };
+            let polygon = clip::clip_triangle([
+                corner(t1, n1, uv1),
+                corner(t2, n2, uv2),
+                corner(t3, n3, uv3),
+            ]);
+            if polygon.is_empty() {
+                stats.outside += 1;
+                continue;
+            }
+            let screen_polygon: Vec<Vertex> = polygon
+                .iter()
+                .map(|v| project(screen_from_ndc, v.position))
+                .collect();
+            if self.is_culled(&screen_polygon) {
+                stats.culled += 1;
+                continue;
+            }
+            stats.drawn += 1;
+
+            // clipping can turn the triangle into a convex polygon, so draw it as a fan
+            for j in 1..polygon.len() - 1 {
+                let corners = [polygon[0], polygon[j], polygon[j + 1]];
+                let vert_tri = Tri::new(
+                    [screen_polygon[0], screen_polygon[j], screen_polygon[j + 1]],
+                    Vec3::from_array(corners.map(|v| 1. / v.position.w)),
+                    corners.map(|v| v.varyings),
+                    TriangleId {
+                        mesh: mesh_id,
+                        triangle: i,
+                    },
+                );
+                poly::draw_tri(buffer, &vert_tri);
+            }
+        }
+        buffer.depth_only = false;
+        stats
+    }
+
+    fn render_overlay(&self, buffer: &mut Buffer, transform: Affine3A, camera: &Camera) {
+        match self.render_mode {
+            RenderMode::Shaded => {}
+            RenderMode::Wireframe(wireframe) | RenderMode::ShadedWireframe(wireframe) => {
+                self.render_wireframe(buffer, transform, camera, wireframe)
+            }
         }
     }
 }
 
+impl Mesh {
+    fn render_wireframe(
+        &self,
+        buffer: &mut Buffer,
+        transform: Affine3A,
+        camera: &Camera,
+        wireframe: Wireframe,
+    ) {
+        let clip_from_model =
+            camera.view_projection(buffer.width(), buffer.height()) * Mat4::from(transform);
+        let screen_from_ndc = camera::viewport(buffer.width(), buffer.height());
+        let clip_verts: Vec<Vec4> = self
+            .vertices
+            .iter()
+            .map(|v| clip_from_model * v.extend(1.))
+            .collect();
+
+        // edges are shared between triangles, so collect them to draw each once
+        let mut edges = HashSet::new();
+        for triangle in &self.triangles {
+            let (t1, t2, t3) = triangle.index;
+            let polygon = clip::clip_triangle([clip_verts[t1], clip_verts[t2], clip_verts[t3]]);
+            if polygon.is_empty() {
+                continue;
+            }
+            let screen_polygon: Vec<Vertex> = polygon
+                .into_iter()
+                .map(|v| project(screen_from_ndc, v))
+                .collect();
+            if self.is_culled(&screen_polygon) {
+                continue;
+            }
+            for (a, b) in [(t1, t2), (t2, t3), (t3, t1)] {
+                edges.insert((a.min(b), a.max(b)));
+            }
+        }
+        edges.extend(self.lines.iter().map(|&(a, b)| (a.min(b), a.max(b))));
+
+        for (a, b) in edges {
+            if let Some((a, b)) = clip::clip_line(clip_verts[a], clip_verts[b]) {
+                buffer.line(
+                    project(screen_from_ndc, a),
+                    project(screen_from_ndc, b),
+                    wireframe.color,
+                    wireframe.depth_test,
+                );
+            }
+        }
+    }
+}
+
+/// clip space -> screen space, with depth in z
+fn project(screen_from_ndc: Mat4, clip: Vec4) -> Vertex {
+    let ndc = clip.xyz() / clip.w;
+    screen_from_ndc.transform_point3(ndc)
+}
+
+/// A node in the scene graph
+#[derive(Debug, Clone)]
 pub struct Geo {
-    pub shape: Mesh,         //Box<dyn Shape>,
-    pub transform: Affine3A, // TODO this is currently overwritten in animation.rs
-    _children: Vec<Geo>,
+    pub name: String,
+    pub shape: Option<Mesh>, //Box<dyn Shape>,
+    /// relative to the parent node
+    pub transform: Affine3A,
+    pub children: Vec<Geo>,
 }
 
 impl Geo {
-    pub fn new(shape: Mesh, transform: Affine3A) -> Geo {
+    pub fn new(name: impl Into<String>, shape: Option<Mesh>, transform: Affine3A) -> Geo {
         Geo {
+            name: name.into(),
             shape,
             transform,
-            _children: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    pub fn with_child(mut self, child: Geo) -> Geo {
+        self.children.push(child);
+        self
+    }
+
+    /// Depth first search of this node and its descendants
+    pub fn find(&self, name: &str) -> Option<&Geo> {
+        if self.name == name {
+            return Some(self);
+        }
+        self.children.iter().find_map(|child| child.find(name))
+    }
+
+    pub fn find_mut(&mut self, name: &str) -> Option<&mut Geo> {
+        if self.name == name {
+            return Some(self);
+        }
+        self.children
+            .iter_mut()
+            .find_map(|child| child.find_mut(name))
+    }
+
+    /// Visits every node depth first, parents before children, along with
+    /// its world transform. `parent` is the world transform of this node's parent.
+    pub fn traverse<'a>(&'a self, parent: Affine3A, f: &mut impl FnMut(&'a Geo, Affine3A)) {
+        let world = parent * self.transform;
+        f(self, world);
+        for child in &self.children {
+            child.traverse(world, f);
+        }
+    }
+
+    /// Every mesh in the tree with its world transform, in traversal order.
+    /// The position in this list is the mesh id used in the G-buffer.
+    pub fn meshes(&self) -> Vec<(&Mesh, Affine3A)> {
+        let mut meshes = Vec::new();
+        self.traverse(Affine3A::IDENTITY, &mut |geo, world| {
+            if let Some(mesh) = &geo.shape {
+                meshes.push((mesh, world));
+            }
+        });
+        meshes
+    }
+
+    /// Same order as `Geo::meshes`
+    pub fn meshes_mut(&mut self) -> Vec<&mut Mesh> {
+        let mut meshes = Vec::new();
+        self.collect_meshes_mut(&mut meshes);
+        meshes
+    }
+
+    fn collect_meshes_mut<'a>(&'a mut self, meshes: &mut Vec<&'a mut Mesh>) {
+        if let Some(mesh) = &mut self.shape {
+            meshes.push(mesh);
+        }
+        for child in &mut self.children {
+            child.collect_meshes_mut(meshes);
         }
     }
-    pub fn deferred_render(&self, buffer: &mut Buffer) {
-        self.shape.render(buffer, self.transform);
+
+    /// Renders every mesh in the tree into the G-buffer
+    pub fn deferred_render(
+        &self,
+        buffer: &mut Buffer,
+        camera: &Camera,
+        lights: &[Light],
+    ) -> RenderStats {
+        let mut stats = RenderStats::default();
+        for (mesh_id, (mesh, world)) in self.meshes().into_iter().enumerate() {
+            stats += mesh.render(buffer, mesh_id, world, camera, lights);
+        }
+        stats
+    }
+
+    pub fn overlay_render(&self, buffer: &mut Buffer, camera: &Camera) {
+        for (mesh, world) in self.meshes() {
+            mesh.render_overlay(buffer, world, camera);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::Vec3;
+
+    use super::*;
+
+    #[test]
+    fn transforms_propagate_to_children() {
+        let scene = Geo::new("root", None, Affine3A::from_translation(Vec3::X)).with_child(
+            Geo::new("arm", None, Affine3A::from_scale(Vec3::splat(2.))).with_child(Geo::new(
+                "hand",
+                None,
+                Affine3A::from_translation(Vec3::Y),
+            )),
+        );
+        let mut hand = None;
+        scene.traverse(Affine3A::IDENTITY, &mut |geo, world| {
+            if geo.name == "hand" {
+                hand = Some(world.transform_point3(Vec3::ZERO));
+            }
+        });
+        assert_eq!(Some(Vec3::new(1., 2., 0.)), hand);
+    }
+
+    #[test]
+    fn area_of_clipped_polygons() {
+        let square = [Vec3::ZERO, Vec3::X, Vec3::new(1., 1., 0.), Vec3::Y];
+        assert_eq!(2., signed_area(&square));
+        let mut reversed = square;
+        reversed.reverse();
+        assert_eq!(-2., signed_area(&reversed));
+        // the first three vertices alone have no area
+        let coincident = [Vec3::ZERO, Vec3::ZERO, Vec3::X, Vec3::new(1., 1., 0.)];
+        assert_eq!(1., signed_area(&coincident));
+        let collinear = [Vec3::ZERO, Vec3::X, Vec3::new(2., 0., 0.), Vec3::Y];
+        assert_eq!(2., signed_area(&collinear));
+    }
+
+    #[test]
+    fn find_by_name() {
+        let mut scene = Geo::new("root", None, Affine3A::IDENTITY)
+            .with_child(Geo::new("a", None, Affine3A::IDENTITY))
+            .with_child(Geo::new("b", None, Affine3A::IDENTITY).with_child(Geo::new(
+                "c",
+                None,
+                Affine3A::IDENTITY,
+            )));
+        assert_eq!("c", scene.find("c").unwrap().name);
+        assert!(scene.find("d").is_none());
+        scene.find_mut("a").unwrap().transform = Affine3A::from_translation(Vec3::Z);
+        assert_eq!(
+            Vec3::Z,
+            scene.find("a").unwrap().transform.translation.into()
+        );
     }
 }
@@ -0,0 +1,553 @@
+use std::{collections::HashMap, fmt, path::Path};
+
+use assets_manager::{
+    loader::{BytesLoader, LoadFrom},
+    AnyCache, Asset, BoxedError, Compound, SharedString,
+};
+use glam::{Affine3A, Quat, Vec2, Vec3};
+
+use crate::{
+    color::{Material, Materials, NamedMaterials, Palette},
+    geo::{self, CullMode, Geo, IndexedTriangle, Mesh, RenderMode, Winding},
+    mtl, obj,
+};
+
+#[derive(Debug)]
+pub enum GltfError {
+    Gltf(::gltf::Error),
+    /// a .glb buffer without the binary chunk
+    MissingBinary,
+    NoScene,
+    MissingPositions {
+        mesh: String,
+    },
+    /// an index that refers to no vertex of its primitive
+    BadIndex {
+        mesh: String,
+        index: usize,
+        count: usize,
+    },
+}
+
+impl fmt::Display for GltfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GltfError::Gltf(e) => write!(f, "{e}"),
+            GltfError::MissingBinary => f.write_str("missing the binary chunk of the .glb"),
+            GltfError::NoScene => f.write_str("no scene to load"),
+            GltfError::MissingPositions { mesh } => write!(f, "mesh {mesh} has no positions"),
+            GltfError::BadIndex { mesh, index, count } => write!(
+                f,
+                "mesh {mesh} refers to vertex {index}, but only has {count}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GltfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GltfError::Gltf(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<::gltf::Error> for GltfError {
+    fn from(value: ::gltf::Error) -> Self {
+        GltfError::Gltf(value)
+    }
+}
+
+/// The raw bytes of a .glb or .gltf file
+pub struct GltfFile(Vec<u8>);
+
+impl From<Vec<u8>> for GltfFile {
+    fn from(value: Vec<u8>) -> Self {
+        GltfFile(value)
+    }
+}
+
+impl Asset for GltfFile {
+    const EXTENSIONS: &'static [&'static str] = &["glb", "gltf"];
+    type Loader = LoadFrom<Vec<u8>, BytesLoader>;
+}
+
+/// A buffer stored next to a .gltf file
+pub struct BinFile(Vec<u8>);
+
+impl From<Vec<u8>> for BinFile {
+    fn from(value: Vec<u8>) -> Self {
+        BinFile(value)
+    }
+}
+
+impl Asset for BinFile {
+    const EXTENSION: &'static str = "bin";
+    type Loader = LoadFrom<Vec<u8>, BytesLoader>;
+}
+
+/// A glTF document with the contents of its buffers
+pub struct GltfData {
+    pub document: ::gltf::Document,
+    pub buffers: Vec<Vec<u8>>,
+}
+
+impl GltfData {
+    /// Parses a .glb or .gltf file. Buffers embedded as base64 `data:` uris
+    /// are decoded, and the contents of other uris come from `load_uri`.
+    pub fn from_slice(
+        bytes: &[u8],
+        mut load_uri: impl FnMut(&str) -> Result<Vec<u8>, BoxedError>,
+    ) -> Result<Self, BoxedError> {
+        let ::gltf::Gltf { document, blob } =
+            ::gltf::Gltf::from_slice(bytes).map_err(GltfError::from)?;
+        let buffers = document
+            .buffers()
+            .map(|buffer| match buffer.source() {
+                ::gltf::buffer::Source::Bin => {
+                    blob.clone().ok_or_else(|| GltfError::MissingBinary.into())
+                }
+                source @ ::gltf::buffer::Source::Uri(uri) if uri.starts_with("data:") => {
+                    let data =
+                        ::gltf::buffer::Data::from_source(source, None).map_err(GltfError::from)?;
+                    Ok(data.0)
+                }
+                ::gltf::buffer::Source::Uri(uri) => load_uri(uri),
+            })
+            .collect::<Result<_, BoxedError>>()?;
+        Ok(GltfData { document, buffers })
+    }
+}
+
+/// Loads `<id>.glb` or `<id>.gltf`, with the .bin files the latter refers to
+impl Compound for GltfData {
+    fn load(cache: AnyCache, id: &SharedString) -> Result<Self, BoxedError> {
+        let file = cache.load::<GltfFile>(id)?;
+        GltfData::from_slice(&file.read().0, |uri| {
+            let bin_id = obj::sibling_id(id, &percent_decode(uri)?)?;
+            Ok(cache.load::<BinFile>(&bin_id)?.read().0.clone())
+        })
+    }
+}
+
+/// Replaces the `%XX` escapes of a uri with the bytes they stand for
+fn percent_decode(uri: &str) -> Result<String, String> {
+    let invalid = || format!("{uri} is not a valid uri");
+    let mut bytes = Vec::with_capacity(uri.len());
+    let mut rest = uri.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        rest = after;
+        if byte == b'%' {
+            let hex = rest
+                .get(..2)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                .ok_or_else(invalid)?;
+            // two hex digits are ascii and always fit in a byte
+            let hex = std::str::from_utf8(hex).unwrap();
+            bytes.push(u8::from_str_radix(hex, 16).unwrap());
+            rest = &rest[2..];
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+/// Loads the root nodes of the default scene of the glTF file `id`.
+///
+/// Materials are looked up by name in the toml file `materials_id`. Materials
+/// that aren't there get a shade ramp generated from their base color with
+/// the palette `palette_id`, like the ones of .mtl files.
+pub fn load_scene(
+    cache: AnyCache,
+    id: &str,
+    materials_id: Option<&str>,
+    palette_id: &str,
+) -> Result<Vec<Geo>, BoxedError> {
+    let data = cache.load::<GltfData>(id)?.read();
+    let palette = cache.load::<Palette>(palette_id)?.read();
+    let named_materials = match materials_id {
+        Some(materials_id) => cache
+            .load::<NamedMaterials>(materials_id)?
+            .cloned()
+            .checked(&palette),
+        None => NamedMaterials::default(),
+    };
+    let mut nodes = scene_nodes(&data.document, &data.buffers, &named_materials, &palette)?;
+    // so edits of the materials can be saved to the toml file
+    for node in &mut nodes {
+        for mesh in node.meshes_mut() {
+            mesh.materials.source = materials_id.map(str::to_string);
+        }
+    }
+    Ok(nodes)
+}
+
+/// Reads a glTF file straight from disk, see `load_scene`
+pub fn load_file(
+    path: &Path,
+    named_materials: &NamedMaterials,
+    palette: &Palette,
+) -> Result<Vec<Geo>, GltfError> {
+    let (document, buffers, _images) = ::gltf::import(path)?;
+    let buffers: Vec<Vec<u8>> = buffers.into_iter().map(|data| data.0).collect();
+    scene_nodes(&document, &buffers, named_materials, palette)
+}
+
+fn scene_nodes(
+    document: &::gltf::Document,
+    buffers: &[Vec<u8>],
+    named_materials: &NamedMaterials,
+    palette: &Palette,
+) -> Result<Vec<Geo>, GltfError> {
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or(GltfError::NoScene)?;
+    scene
+        .nodes()
+        .map(|node| build_node(node, buffers, named_materials, palette))
+        .collect()
+}
+
+fn build_node(
+    node: ::gltf::Node,
+    buffers: &[Vec<u8>],
+    named_materials: &NamedMaterials,
+    palette: &Palette,
+) -> Result<Geo, GltfError> {
+    let (translation, rotation, scale) = node.transform().decomposed();
+    let transform = Affine3A::from_scale_rotation_translation(
+        Vec3::from(scale),
+        Quat::from_array(rotation),
+        Vec3::from(translation),
+    );
+    let name = node
+        .name()
+        .map_or_else(|| format!("node{}", node.index()), str::to_string);
+    let shape = node
+        .mesh()
+        .map(|mesh| build_mesh(mesh, buffers, named_materials, palette))
+        .transpose()?;
+
+    let mut geo = Geo::new(name, shape, transform);
+    for child in node.children() {
+        geo.children
+            .push(build_node(child, buffers, named_materials, palette)?);
+    }
+    Ok(geo)
+}
+
+/// All triangle primitives of `mesh` merged into one `Mesh`
+fn build_mesh(
+    mesh: ::gltf::Mesh,
+    buffers: &[Vec<u8>],
+    named_materials: &NamedMaterials,
+    palette: &Palette,
+) -> Result<Mesh, GltfError> {
+    let mesh_name = mesh
+        .name()
+        .map_or_else(|| format!("mesh{}", mesh.index()), str::to_string);
+    let mut vertices: Vec<Vec3> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+    // kept parallel to `vertices`, so vertex indices are also uv indices
+    let mut uvs: Vec<Vec2> = Vec::new();
+    let mut triangles = Vec::new();
+    let mut materials: Vec<Material> = Vec::new();
+    let mut names: Vec<String> = Vec::new();
+    // glTF material index -> index into `materials`
+    let mut material_indices: HashMap<Option<usize>, usize> = HashMap::new();
+
+    for primitive in mesh.primitives() {
+        if primitive.mode() != ::gltf::mesh::Mode::Triangles {
+            log::warn!(
+                "glTF: Skipping {:?} primitive of mesh {mesh_name}",
+                primitive.mode()
+            );
+            continue;
+        }
+        let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+
+        let positions: Vec<Vec3> = reader
+            .read_positions()
+            .ok_or_else(|| GltfError::MissingPositions {
+                mesh: mesh_name.clone(),
+            })?
+            .map(Vec3::from)
+            .collect();
+        let indices: Vec<usize> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().map(|i| i as usize).collect(),
+            None => (0..positions.len()).collect(),
+        };
+        if let Some(&index) = indices.iter().find(|&&i| i >= positions.len()) {
+            return Err(GltfError::BadIndex {
+                mesh: mesh_name,
+                index,
+                count: positions.len(),
+            });
+        }
+        let faces: Vec<(usize, usize, usize)> = indices
+            .chunks_exact(3)
+            .map(|t| (t[0], t[1], t[2]))
+            .collect();
+
+        let primitive_normals: Vec<Vec3> = match reader.read_normals() {
+            Some(normals) => normals.map(|n| Vec3::from(n).normalize_or_zero()).collect(),
+            None => geo::vertex_normals(&positions, &faces),
+        };
+        let primitive_uvs: Option<Vec<Vec2>> = reader.read_tex_coords(0).map(|uvs| {
+            uvs.into_f32()
+                // glTF has v pointing down, OBJ and `Texture` have it up
+                .map(|[u, v]| Vec2::new(u, 1. - v))
+                .collect()
+        });
+
+        let material = primitive.material();
+        let material_index = *material_indices.entry(material.index()).or_insert_with(|| {
+            let name = match (material.name(), material.index()) {
+                (Some(name), _) => name.to_string(),
+                (None, Some(index)) => format!("material{index}"),
+                // primitives without a material
+                (None, None) => "default".to_string(),
+            };
+            let named = named_materials.0.get(&name).cloned();
+            names.push(name);
+            materials.push(named.unwrap_or_else(|| {
+                let [r, g, b, _] = material.pbr_metallic_roughness().base_color_factor();
+                Material {
+                    shades: mtl::shade_ramp(Vec3::new(r, g, b), palette),
+                    curve: None,
+                    shader: None,
+                }
+            }));
+            materials.len() - 1
+        });
+
+        let offset = vertices.len();
+        for &(a, b, c) in &faces {
+            let index = (offset + a, offset + b, offset + c);
+            triangles.push(IndexedTriangle {
+                index,
+                normal_index: index,
+                uv_index: primitive_uvs.is_some().then_some(index),
+                material_index,
+            });
+        }
+        vertices.extend(positions);
+        normals.extend(primitive_normals);
+        match primitive_uvs {
+            Some(primitive_uvs) => uvs.extend(primitive_uvs),
+            None => uvs.resize(vertices.len(), Vec2::ZERO),
+        }
+    }
+
+    Ok(Mesh {
+        vertices,
+        normals,
+        uvs,
+        triangles,
+        lines: Vec::new(),
+        materials: Materials::new(materials, names),
+        texture: None,
+        cull_mode: CullMode::default(),
+        winding: Winding::default(),
+        render_mode: RenderMode::default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// A quad with indices for its two triangles, and a triangle with an
+    /// index past the quad's last vertex
+    fn buffer() -> Vec<u8> {
+        let positions = [[0f32, 0., 0.], [1., 0., 0.], [1., 1., 0.], [0., 1., 0.]];
+        let mut bytes: Vec<u8> = positions
+            .iter()
+            .flatten()
+            .flat_map(|f| f.to_le_bytes())
+            .collect();
+        for index in [0u16, 1, 2, 0, 2, 3, 0, 1, 4, 0] {
+            bytes.extend(index.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn base64(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut encoded = String::new();
+        for chunk in bytes.chunks(3) {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+            for i in 0..4 {
+                encoded.push(match i <= chunk.len() {
+                    true => ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char,
+                    false => '=',
+                });
+            }
+        }
+        encoded
+    }
+
+    /// A .gltf file whose root node shows mesh `root_mesh`, with its buffer at `uri`
+    fn gltf(uri: &str, root_mesh: usize) -> String {
+        format!(
+            r#"{{
+            "asset": {{ "version": "2.0" }},
+            "scene": 0,
+            "scenes": [{{ "nodes": [0] }}],
+            "nodes": [
+                {{
+                    "name": "parent",
+                    "mesh": {root_mesh},
+                    "translation": [1, 2, 3],
+                    "rotation": [0, 0, 0.70710677, 0.70710677],
+                    "scale": [2, 2, 2],
+                    "children": [1]
+                }},
+                {{ "matrix": [3, 0, 0, 0, 0, 3, 0, 0, 0, 0, 3, 0, 0, 0, 5, 1] }}
+            ],
+            "meshes": [
+                {{
+                    "name": "quad",
+                    "primitives": [
+                        {{ "attributes": {{ "POSITION": 0 }}, "indices": 1, "material": 0 }},
+                        {{ "attributes": {{ "POSITION": 0 }}, "indices": 1, "material": 1 }},
+                        {{ "attributes": {{ "POSITION": 0 }}, "indices": 1 }},
+                        {{ "attributes": {{ "POSITION": 0 }}, "indices": 1, "material": 0 }}
+                    ]
+                }},
+                {{
+                    "name": "broken",
+                    "primitives": [{{ "attributes": {{ "POSITION": 0 }}, "indices": 2 }}]
+                }}
+            ],
+            "materials": [
+                {{ "name": "red", "pbrMetallicRoughness": {{ "baseColorFactor": [1, 0, 0, 1] }} }},
+                {{ "pbrMetallicRoughness": {{ "baseColorFactor": [0, 0, 1, 1] }} }}
+            ],
+            "accessors": [
+                {{
+                    "bufferView": 0, "componentType": 5126, "count": 4, "type": "VEC3",
+                    "min": [0, 0, 0], "max": [1, 1, 0]
+                }},
+                {{ "bufferView": 1, "componentType": 5123, "count": 6, "type": "SCALAR" }},
+                {{ "bufferView": 1, "byteOffset": 12, "componentType": 5123, "count": 3, "type": "SCALAR" }}
+            ],
+            "bufferViews": [
+                {{ "buffer": 0, "byteLength": 48 }},
+                {{ "buffer": 0, "byteOffset": 48, "byteLength": 20 }}
+            ],
+            "buffers": [{{ "byteLength": 68, "uri": "{uri}" }}]
+        }}"#
+        )
+    }
+
+    fn palette() -> Palette {
+        Palette {
+            colors: vec![0x000000FF, 0xFF0000FF, 0x0000FFFF, 0xFFFFFFFF],
+        }
+    }
+
+    fn load(gltf: &str, named_materials: &NamedMaterials) -> Result<Vec<Geo>, BoxedError> {
+        let data = GltfData::from_slice(gltf.as_bytes(), |uri| match uri {
+            "quad.bin" => Ok(buffer()),
+            _ => Err(format!("no file {uri}").into()),
+        })?;
+        Ok(scene_nodes(
+            &data.document,
+            &data.buffers,
+            named_materials,
+            &palette(),
+        )?)
+    }
+
+    #[test]
+    fn transforms() {
+        let nodes = load(&gltf("quad.bin", 0), &NamedMaterials::default()).unwrap();
+        assert_eq!(1, nodes.len());
+        let parent = &nodes[0];
+        assert_eq!("parent", parent.name);
+        // scaled, turned a quarter around z, then moved
+        let moved = parent.transform.transform_point3(Vec3::X);
+        assert!(moved.abs_diff_eq(Vec3::new(1., 4., 3.), 1e-5));
+
+        // nodes without a name are named after their index
+        let child = &parent.children[0];
+        assert_eq!("node1", child.name);
+        assert!(child.shape.is_none());
+        let moved = child.transform.transform_point3(Vec3::ONE);
+        assert!(moved.abs_diff_eq(Vec3::new(3., 3., 8.), 1e-5));
+    }
+
+    #[test]
+    fn primitives_are_merged() {
+        let red = Material {
+            shades: vec![1, 1],
+            curve: None,
+            shader: None,
+        };
+        let named = NamedMaterials(HashMap::from([("red".to_string(), red)]));
+        let nodes = load(&gltf("quad.bin", 0), &named).unwrap();
+        let mesh = nodes[0].shape.as_ref().unwrap();
+
+        // every primitive gets its own copy of the vertices
+        assert_eq!(16, mesh.vertices.len());
+        assert_eq!(16, mesh.normals.len());
+        assert_eq!(16, mesh.uvs.len());
+        assert_eq!(8, mesh.triangles.len());
+        assert_eq!((12, 14, 15), mesh.triangles[7].index);
+        assert!(mesh.triangles.iter().all(|t| t.uv_index.is_none()));
+        // generated normals face the viewer of the counter clockwise quad
+        assert!(mesh.normals.iter().all(|n| n.abs_diff_eq(Vec3::Z, 1e-6)));
+
+        // primitives with the same material share it
+        let material_indices: Vec<usize> =
+            mesh.triangles.iter().map(|t| t.material_index).collect();
+        assert_eq!(vec![0, 0, 1, 1, 2, 2, 0, 0], material_indices);
+        assert_eq!(vec!["red", "material1", "default"], mesh.materials.names);
+        // named materials come from the toml file, others from their base color
+        assert_eq!(vec![1, 1], mesh.materials.materials[0].shades);
+        assert_eq!(Some(&2), mesh.materials.materials[1].shades.last());
+        assert_eq!(Some(&3), mesh.materials.materials[2].shades.last());
+    }
+
+    #[test]
+    fn embedded_buffers() {
+        let uri = format!("data:application/octet-stream;base64,{}", base64(&buffer()));
+        let embedded = load(&gltf(&uri, 0), &NamedMaterials::default()).unwrap();
+        let separate = load(&gltf("quad.bin", 0), &NamedMaterials::default()).unwrap();
+        assert_eq!(
+            separate[0].shape.as_ref().unwrap().vertices,
+            embedded[0].shape.as_ref().unwrap().vertices
+        );
+        assert!(load(&gltf("missing.bin", 0), &NamedMaterials::default()).is_err());
+    }
+
+    #[test]
+    fn uris_are_percent_decoded() {
+        assert_eq!("my mesh.bin", percent_decode("my%20mesh.bin").unwrap());
+        assert_eq!("ü.bin", percent_decode("%C3%BC.bin").unwrap());
+        assert_eq!("100%", percent_decode("100%25").unwrap());
+        assert!(percent_decode("100%").is_err());
+        assert!(percent_decode("%zz.bin").is_err());
+        assert!(percent_decode("%FF.bin").is_err());
+    }
+
+    #[test]
+    fn bad_index() {
+        let error = load(&gltf("quad.bin", 1), &NamedMaterials::default()).unwrap_err();
+        match error.downcast_ref::<GltfError>() {
+            Some(GltfError::BadIndex { mesh, index, count }) => {
+                assert_eq!(("broken", 4, 4), (mesh.as_str(), *index, *count));
+            }
+            _ => panic!("expected a bad index, got {error}"),
+        }
+    }
+}
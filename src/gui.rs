@@ -1,3 +1,4 @@
+use assets_manager::{AssetCache, Handle};
 use icecube::mouse_area::MouseArea;
 use icecube::palette::Color;
 use icecube::quad::Quad;
@@ -15,12 +16,18 @@ use icecube::{col, font, row};
 
 use crate::animation::{self};
 use crate::buffer::Buffer;
-use crate::constants::COLOR_DEPTH;
+use crate::export;
+use crate::geo::RenderStats;
 use crate::gui::color_picker::PixelPicker;
 use crate::model::{draw, Model};
 
 mod color_picker;
 
+/// 0xRRGGBBAA colors of the controls, drawn with the nearest palette colors
+const FILL_COLOR: u32 = 0x343473FF;
+const BORDER_COLOR: u32 = 0xF7C93EFF;
+const TEXT_COLOR: u32 = 0xF0EDD8FF;
+
 #[derive(Debug, Copy, Clone)]
 pub enum Message {
     Invert,
@@ -30,35 +37,83 @@ pub enum Message {
     SelectColor(u8),
     SelectMaterial(usize, usize),
     PaintShade(usize),
+    /// writes the selected mesh's materials back to their toml file
+    SaveMaterials,
 }
 
 pub struct State {
     data: Vec<usize>,
     model: Model,
+    cache: &'static AssetCache,
+    /// `model` is a copy of this, replaced when the scene is hot-reloaded
+    model_handle: &'static Handle<Model>,
     buffer: Buffer,
     start_instant: Instant,
     x_rotation: f32,
     y_rotation: f32,
     selected_color: u8,
+    /// mesh id of the mesh whose materials are being edited
+    selected_mesh: usize,
     selected_material: usize,
+    stats: RenderStats,
 }
 
 impl State {
-    pub fn new(buffer: Buffer, model: Model) -> Self {
+    pub fn new(
+        buffer: Buffer,
+        model: Model,
+        cache: &'static AssetCache,
+        model_handle: &'static Handle<Model>,
+    ) -> Self {
         Self {
             data: vec![0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1],
             model,
+            cache,
+            model_handle,
             buffer,
             start_instant: Instant::now(),
             x_rotation: 0.0,
             y_rotation: 0.0,
             selected_color: Default::default(),
+            selected_mesh: 0,
             selected_material: 0,
+            stats: RenderStats::default(),
         }
     }
 }
 
 impl State {
+    /// Saving triggers a hot reload, which reloads the saved materials
+    fn save_materials(&self) {
+        let Some((mesh, _)) = self.model.scene.meshes().get(self.selected_mesh).copied() else {
+            return;
+        };
+        let Some(id) = &mesh.materials.source else {
+            log::warn!("The selected materials weren't loaded from a toml file, so can't be saved");
+            return;
+        };
+        if mesh.materials.edited.is_empty() {
+            log::info!("No edited materials to save");
+            return;
+        }
+        let path = self.cache.raw_source().path_of(id, "toml");
+        match export::save_materials(&path, &mesh.materials, &self.model.palette) {
+            Ok(skipped) => {
+                let all_skipped = skipped.len() == mesh.materials.edited.len();
+                for name in skipped {
+                    log::warn!(
+                        "Material {name} in {} doesn't fit the palette, so edits of it weren't saved",
+                        path.display()
+                    );
+                }
+                if !all_skipped {
+                    log::info!("Saved materials to {}", path.display());
+                }
+            }
+            Err(e) => log::error!("Couldn't save materials to {}: {e}", path.display()),
+        }
+    }
+
     fn invert(&mut self) {
         self.data = self
             .data
@@ -80,25 +135,44 @@ pub fn update(m: Message, state: &mut State) {
         Message::RotateY(radians) => state.y_rotation = radians,
         Message::SelectColor(color) => state.selected_color = color,
         Message::SelectMaterial(x, y) => {
-            let tri = state.buffer.tri_idx_at_pixel(x, y);
+            let tri = state.buffer.tri_id_at_pixel(x, y);
             if let Some(tri) = tri {
-                state.selected_material = state.model.cube.shape.triangles[tri].material_index;
+                // the buffer can be from before the scene was reloaded
+                let meshes = state.model.scene.meshes();
+                if let Some(triangle) = meshes
+                    .get(tri.mesh)
+                    .and_then(|(mesh, _)| mesh.triangles.get(tri.triangle))
+                {
+                    state.selected_mesh = tri.mesh;
+                    state.selected_material = triangle.material_index;
+                }
             }
         }
         Message::PaintShade(i) => {
-            state.model.cube.shape.materials.0[state.selected_material].shades[i] =
-                state.selected_color;
+            if let Some(mesh) = state.model.scene.meshes_mut().get_mut(state.selected_mesh) {
+                mesh.materials
+                    .set_shade(state.selected_material, i, state.selected_color);
+            }
         }
+        Message::SaveMaterials => state.save_materials(),
     }
 }
 
 fn render(duration: Duration, state: &mut State) {
-    // cache.hot_reload(); // TODO turn back on?
-    // buffer.palette = palette_handle.read().colors;
-    //TODO: figure out how to get Materials out of the AssetReadGuard without cloning
-    // model.cube.shape.materials = NamedMaterials(material_handle.read().0.clone()).into();
+    state.cache.hot_reload();
+    if state.model_handle.reloaded_global() {
+        // this throws away unsaved material edits
+        state.model = state.model_handle.cloned();
+        state.buffer.set_palette(&state.model.palette);
+        // the old palette may have been bigger
+        // 256 colors don't fit in a u8, but their last index does
+        let last_color = (state.model.palette.colors.len() - 1) as u8;
+        state.selected_color = state.selected_color.min(last_color);
+        state.selected_mesh = 0;
+        state.selected_material = 0;
+    }
 
-    draw(&mut state.buffer, &state.model);
+    state.stats = draw(&mut state.buffer, &state.model);
 
     // let timing = Timing {
     //     time_since_start: Instant::now() - state.start_instant,
@@ -129,9 +203,12 @@ pub fn view<'a>(state: &State) -> Node<'a, Message, Layout> {
 
     mouse_image_wrapper.push(image);
 
-    let fill_color = ToBytes::to_be_bytes(&state.buffer.palette[3]);
-    let border_color = ToBytes::to_be_bytes(&state.buffer.palette[20]);
-    let text_color = ToBytes::to_be_bytes(&state.buffer.palette[8]);
+    let palette = state.buffer.palette();
+    let theme_color =
+        |color| index_to_icecube_color(state.model.palette.nearest_to(color), palette);
+    let fill_color = theme_color(FILL_COLOR);
+    let border_color = theme_color(BORDER_COLOR);
+    let text_color = theme_color(TEXT_COLOR);
 
     let rotation_label = Node::new(
         Text::new(format!(
@@ -139,10 +216,16 @@ pub fn view<'a>(state: &State) -> Node<'a, Message, Layout> {
             state.x_rotation * 360. / (2. * PI)
         ))
         .with_font(&font::BLACKLETTER)
-        .with_color(index_to_icecube_color(
-            state.selected_color,
-            state.buffer.palette,
-        )),
+        .with_color(index_to_icecube_color(state.selected_color, palette)),
+    );
+
+    let stats_label = Node::new(
+        Text::new(format!(
+            "Drawn: {} Culled: {} Outside: {}",
+            state.stats.drawn, state.stats.culled, state.stats.outside
+        ))
+        .with_font(&font::BLACKLETTER)
+        .with_color(text_color),
     );
 
     let x_rotation_slider: Node<_, _> = Slider::new(-PI..PI, state.x_rotation)
@@ -154,52 +237,67 @@ pub fn view<'a>(state: &State) -> Node<'a, Message, Layout> {
         .set_color(border_color, fill_color, text_color)
         .into();
 
-    let img_data: Vec<Color> = state
-        .buffer
-        .palette
-        .clone()
-        .into_iter()
-        .map(|px| ToBytes::to_be_bytes(&px))
-        .collect();
+    // big palettes get more columns, so the picker doesn't get too tall
+    let picker_width = if palette.len() > 64 { 16 } else { 8 };
+    let picker_height = palette.len().div_ceil(picker_width);
+    let mut img_data: Vec<Color> = palette.iter().map(ToBytes::to_be_bytes).collect();
+    // the last row is filled up with transparent pixels, which pick nothing
+    img_data.resize(picker_width * picker_height, [0; 4]);
 
+    let selected_color = state.selected_color;
     let color_picker = PixelPicker {
-        w: 8,
-        h: img_data.len() / 8,
+        w: picker_width,
+        h: picker_height,
         scale: 8,
-        img_data: img_data,
-        palette: state.buffer.palette.to_vec(),
-        on_press: |_, _, idx| Message::SelectColor(idx.unwrap_or_default() as u8),
+        img_data,
+        palette: palette.to_vec(),
+        on_press: move |_, _, idx| {
+            Message::SelectColor(idx.map_or(selected_color, |idx| idx as u8))
+        },
     };
 
-    let material_img: Vec<Color> = state.model.cube.shape.materials.0[state.selected_material]
-        .shades
-        .clone()
-        .into_iter()
-        .map(|i| state.buffer.palette[i as usize])
-        .map(|px| ToBytes::to_be_bytes(&px))
-        .collect();
-
-    let material_panel = PixelPicker {
-        w: 9,
-        h: 1,
-        scale: 8,
-        img_data: material_img,
-        palette: state.buffer.palette.to_vec(), // TODO unused. refactor?
-        on_press: |px, _, _| Message::PaintShade(px),
-    };
+    let mut controls = col![
+        Node::spacer(),
+        rotation_label,
+        stats_label,
+        x_rotation_slider.width(100).height(10),
+        y_rotation_slider.width(100).height(10),
+        color_picker.view(),
+    ]
+    .spacing(10);
+
+    // a scene can have nothing but empty nodes, with no materials to edit
+    if let Some((selected_mesh, _)) = state.model.scene.meshes().get(state.selected_mesh) {
+        let material_img: Vec<Color> = selected_mesh.materials.materials[state.selected_material]
+            .shades
+            .clone()
+            .into_iter()
+            .map(|i| palette[i as usize])
+            .map(|px| ToBytes::to_be_bytes(&px))
+            .collect();
+
+        let material_panel = PixelPicker {
+            w: material_img.len(),
+            h: 1,
+            scale: 8,
+            img_data: material_img,
+            palette: palette.to_vec(), // TODO unused. refactor?
+            on_press: |px, _, _| Message::PaintShade(px),
+        };
+        controls.push(material_panel.view());
+        controls.push(make_button(
+            "Save".to_string(),
+            Message::SaveMaterials,
+            fill_color,
+            border_color,
+            text_color,
+        ));
+    }
+    controls.push(Node::spacer());
 
     row![
         Node::spacer(),
-        col![
-            Node::spacer(),
-            rotation_label,
-            x_rotation_slider.width(100).height(10),
-            y_rotation_slider.width(100).height(10),
-            color_picker.view(),
-            material_panel.view(),
-            Node::spacer()
-        ]
-        .spacing(10),
+        controls,
         Node::spacer(),
         col![Node::spacer(), mouse_image_wrapper, Node::spacer()],
         Node::spacer(),
@@ -207,7 +305,7 @@ pub fn view<'a>(state: &State) -> Node<'a, Message, Layout> {
     .height(Length::Grow)
 }
 
-fn _make_button<'a>(
+fn make_button<'a>(
     label: String,
     action: Message,
     fill_color: Color,
@@ -231,14 +329,14 @@ fn _make_button<'a>(
     .row();
     button_quad.push(button_text);
 
-    let mut button_node = Node::new(Button::new().whenever_down(action))
+    let mut button_node = Node::new(Button::new().on_press(action))
         .width(Length::Shrink)
         .height(Length::Shrink);
     button_node.push(button_quad);
     button_node
 }
 
-fn index_to_icecube_color(color_idx: u8, palette: [u32; COLOR_DEPTH as usize]) -> Color {
+fn index_to_icecube_color(color_idx: u8, palette: &[u32]) -> Color {
     let color_u32 = palette[color_idx as usize];
     ToBytes::to_be_bytes(&color_u32)
 }
@@ -1,4 +1,6 @@
-use glam::Vec2;
+use std::ops::{Add, Mul};
+
+use glam::{Vec2, Vec3};
 
 pub fn lerp(p1: Vec2, p2: Vec2, y: f32) -> f32 {
     let x1 = p1.x;
@@ -62,6 +64,65 @@ impl Iterator for LerpIter {
     }
 }
 
+/// A value that can be interpolated across a triangle
+pub trait Attribute: Copy + Add<Output = Self> + Mul<f32, Output = Self> {}
+
+impl<T> Attribute for T where T: Copy + Add<Output = T> + Mul<f32, Output = T> {}
+
+/// Sum of `values` weighted by barycentric `weights`
+pub fn interpolate<T: Attribute>(values: [T; 3], weights: Vec3) -> T {
+    values[0] * weights.x + values[1] * weights.y + values[2] * weights.z
+}
+
+/// Barycentric coordinates for a triangle in screen space.
+///
+/// Attributes that were linear across the triangle before the perspective
+/// divide are not linear on screen, so `perspective` corrects screen space
+/// weights using each vertex's `1 / w`.
+#[derive(Debug, Clone, Copy)]
+pub struct Barycentric {
+    a: Vec2,
+    ab: Vec2,
+    ac: Vec2,
+    area: f32,
+    inv_w: Vec3,
+}
+
+impl Barycentric {
+    pub fn new(a: Vec2, b: Vec2, c: Vec2, inv_w: Vec3) -> Self {
+        let (ab, ac) = (b - a, c - a);
+        Self {
+            a,
+            ab,
+            ac,
+            area: ab.perp_dot(ac),
+            inv_w,
+        }
+    }
+
+    /// Screen space weights of `p`. Depth can be interpolated with these directly.
+    pub fn weights(&self, p: Vec2) -> Vec3 {
+        if self.area == 0. {
+            return Vec3::X;
+        }
+        let ap = p - self.a;
+        let w2 = ap.perp_dot(self.ac) / self.area;
+        let w3 = self.ab.perp_dot(ap) / self.area;
+        Vec3::new(1. - w2 - w3, w2, w3)
+    }
+
+    /// Converts screen space weights to perspective correct ones
+    pub fn perspective(&self, weights: Vec3) -> Vec3 {
+        let corrected = weights * self.inv_w;
+        let sum = corrected.element_sum();
+        if sum == 0. {
+            weights
+        } else {
+            corrected / sum
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -162,6 +223,33 @@ mod tests {
         let vert: Vec<Point> = LerpIter::new((0., 5.), (0., 0.), 1).collect();
         assert_eq!((0.0, 0.0), vert[0])
     }
+    #[test]
+    fn barycentric_vertices() {
+        let tri = Barycentric::new(
+            Vec2::new(0., 0.),
+            Vec2::new(4., 0.),
+            Vec2::new(0., 4.),
+            Vec3::ONE,
+        );
+        assert_eq!(Vec3::X, tri.weights(Vec2::new(0., 0.)));
+        assert_eq!(Vec3::Y, tri.weights(Vec2::new(4., 0.)));
+        assert_eq!(Vec3::Z, tri.weights(Vec2::new(0., 4.)));
+    }
+
+    #[test]
+    /// Halfway along an edge on screen is closer to the nearer vertex in world space
+    fn perspective_correction() {
+        let tri = Barycentric::new(
+            Vec2::new(0., 0.),
+            Vec2::new(4., 0.),
+            Vec2::new(0., 4.),
+            Vec3::new(1., 1. / 3., 1.),
+        );
+        let weights = tri.perspective(tri.weights(Vec2::new(2., 0.)));
+        assert!(weights.abs_diff_eq(Vec3::new(0.75, 0.25, 0.), 1e-6));
+        assert!((interpolate([2., 6., 0.], weights) - 3.).abs() < 1e-6);
+    }
+
     #[test]
     fn vertical_line_3() {
         let vert: Vec<Point> = LerpIter::new((0., 5.), (0., 0.), 3).collect();
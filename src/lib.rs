@@ -1,10 +1,22 @@
 pub mod animation;
 pub mod buffer;
+pub mod camera;
+pub mod clip;
 pub mod color;
 pub mod constants;
+pub mod export;
 pub mod geo;
+pub mod gltf;
 pub mod gui;
 pub mod interpolate;
+pub mod light;
 pub mod model;
+pub mod mtl;
 pub mod obj;
+pub mod palette;
+pub mod ply;
 pub mod poly;
+pub mod scene;
+pub mod shader;
+pub mod stl;
+pub mod texture;
@@ -0,0 +1,120 @@
+use glam::Vec3;
+use serde::Deserialize;
+
+/// A light source in world space
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Light {
+    /// lights every surface equally, regardless of its orientation
+    Ambient { intensity: f32 },
+    /// parallel light travelling along `direction`, like the sun
+    Directional { direction: Vec3, intensity: f32 },
+    /// light radiating from `position`. There is no falloff with distance.
+    Point { position: Vec3, intensity: f32 },
+}
+
+impl Light {
+    /// Light received at `position` by a surface with unit normal `normal`
+    pub fn illuminate(&self, position: Vec3, normal: Vec3) -> f32 {
+        let diffuse = |to_light: Vec3, intensity: f32| {
+            normal.dot(to_light.normalize_or_zero()).clamp(0.0, 1.0) * intensity
+        };
+        match *self {
+            Light::Ambient { intensity } => intensity,
+            Light::Directional {
+                direction,
+                intensity,
+            } => diffuse(-direction, intensity),
+            Light::Point {
+                position: light_pos,
+                intensity,
+            } => diffuse(light_pos - position, intensity),
+        }
+    }
+}
+
+/// Total light from all `lights` at a point given its world space position and unit normal
+pub fn illumination(lights: &[Light], position: Vec3, normal: Vec3) -> f32 {
+    lights
+        .iter()
+        .map(|light| light.illuminate(position, normal))
+        .sum()
+}
+
+/// A dim ambient light and a bright point light up and to the right of the origin
+pub fn default_lights() -> Vec<Light> {
+    vec![
+        Light::Ambient { intensity: 0.08 },
+        Light::Point {
+            position: Vec3::new(1000.0, 1000.0, 500.0),
+            intensity: 1.0,
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ambient_ignores_orientation() {
+        let light = Light::Ambient { intensity: 0.25 };
+        assert_eq!(0.25, light.illuminate(Vec3::ZERO, Vec3::Y));
+        assert_eq!(0.25, light.illuminate(Vec3::X, -Vec3::Y));
+    }
+
+    #[test]
+    fn directional_depends_on_angle() {
+        let light = Light::Directional {
+            direction: Vec3::new(0.0, -2.0, 0.0),
+            intensity: 0.5,
+        };
+        // facing the light, away from it, side on and at 60 degrees
+        assert_eq!(0.5, light.illuminate(Vec3::ZERO, Vec3::Y));
+        assert_eq!(0.0, light.illuminate(Vec3::ZERO, -Vec3::Y));
+        assert_eq!(0.0, light.illuminate(Vec3::ZERO, Vec3::X));
+        let tilted = Vec3::new(3f32.sqrt() / 2.0, 0.5, 0.0);
+        assert!((0.25 - light.illuminate(Vec3::ZERO, tilted)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn point_lights_towards_position_without_falloff() {
+        let light = Light::Point {
+            position: Vec3::new(0.0, 10.0, 0.0),
+            intensity: 1.0,
+        };
+        assert_eq!(1.0, light.illuminate(Vec3::ZERO, Vec3::Y));
+        assert_eq!(1.0, light.illuminate(Vec3::new(0.0, -90.0, 0.0), Vec3::Y));
+        // the light is beside a point level with it
+        assert_eq!(0.0, light.illuminate(Vec3::new(5.0, 10.0, 0.0), Vec3::Y));
+        assert_eq!(1.0, light.illuminate(Vec3::new(5.0, 10.0, 0.0), -Vec3::X));
+        // a light at the surface itself doesn't light it
+        assert_eq!(0.0, light.illuminate(Vec3::new(0.0, 10.0, 0.0), Vec3::Y));
+    }
+
+    #[test]
+    fn lights_add_up() {
+        let lights = [
+            Light::Ambient { intensity: 0.1 },
+            Light::Directional {
+                direction: -Vec3::Z,
+                intensity: 0.5,
+            },
+            Light::Point {
+                position: Vec3::new(0.0, 0.0, 4.0),
+                intensity: 0.25,
+            },
+        ];
+        assert!((0.85 - illumination(&lights, Vec3::ZERO, Vec3::Z)).abs() < 1e-6);
+        assert!((0.1 - illumination(&lights, Vec3::ZERO, -Vec3::Z)).abs() < 1e-6);
+        assert_eq!(0.0, illumination(&[], Vec3::ZERO, Vec3::Z));
+    }
+
+    #[test]
+    fn default_lights_light_the_front() {
+        let lights = default_lights();
+        let lit = illumination(&lights, Vec3::ZERO, Vec3::new(1.0, 1.0, 0.5).normalize());
+        assert!((1.08 - lit).abs() < 1e-6);
+        assert!((0.08 - illumination(&lights, Vec3::ZERO, -Vec3::Y)).abs() < 1e-6);
+    }
+}
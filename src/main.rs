@@ -6,7 +6,6 @@ use num_traits::ToBytes;
 
 use pyx_rusterizer::{
     buffer::Buffer,
-    color::{NamedMaterials, Palette},
     constants::{HEIGHT, WIDTH},
     gui,
     model::Model,
@@ -15,16 +14,14 @@ use pyx_rusterizer::{
 fn main() {
     // env_logger::init(); // This is done in icecube now. Is that ok?
 
-    let cache = AssetCache::new("assets").unwrap();
-    let palette_handle = cache.load::<Palette>("palette").unwrap();
-    let material_handle = cache.load::<NamedMaterials>("porygon.materials").unwrap();
+    // the gui keeps hot-reloading from the cache until the program exits
+    let cache: &'static AssetCache = Box::leak(Box::new(AssetCache::new("assets").unwrap()));
+    // the scene to show can be picked on the command line, e.g. `cargo run -- scene`
+    let scene_id = std::env::args().nth(1).unwrap_or("scene".to_string());
+    let model_handle = cache.load::<Model>(&scene_id).unwrap();
 
-    let mut buffer: Buffer;
-    {
-        let palette = palette_handle.read();
-
-        buffer = Buffer::new(WIDTH, HEIGHT, palette.colors);
-    }
+    let model = model_handle.cloned();
+    let buffer = Buffer::new(WIDTH, HEIGHT, &model.palette);
 
     /*
     let mut window = Window::new(
@@ -41,25 +38,19 @@ fn main() {
     window.set_target_fps(60);
     */
 
-    let mut model = Model::new(material_handle);
-
     // let start_instant = Instant::now();
     // let mut last_frame_instant = Instant::now();
     // let mut timing: Timing;
 
-    cache.hot_reload();
-    buffer.palette = palette_handle.read().colors;
-    //TODO: figure out how to get Materials out of the AssetReadGuard without cloning
-    model.cube.shape.materials = NamedMaterials(material_handle.read().0.clone()).into();
-
-    let initial_state = gui::State::new(buffer, model);
+    let background = ToBytes::to_be_bytes(&buffer.palette()[buffer.clear_color() as usize]);
+    let initial_state = gui::State::new(buffer, model, cache, model_handle);
     icecube::run(
         initial_state,
         gui::update,
         gui::view,
         320,
         240,
-        ToBytes::to_be_bytes(&palette_handle.read().colors[21]),
+        background,
         |d| Some(gui::Message::TimeElapsed(d)),
     )
     .unwrap();
@@ -1,30 +1,107 @@
-use std::path::Path;
+use assets_manager::{AnyCache, BoxedError, Compound, SharedString};
+use glam::Affine3A;
 
-use assets_manager::Handle;
-use glam::{Affine3A, Vec3};
-
-use crate::{buffer::Buffer, color::NamedMaterials, geo::Geo, obj};
+use crate::{
+    buffer::Buffer,
+    camera::Camera,
+    color::Palette,
+    geo::{Geo, Mesh, RenderStats},
+    gltf,
+    light::Light,
+    obj, ply,
+    scene::{MeshFormat, NodeDescription, SceneDescription},
+    shader::{DitherMask, Textured},
+    stl,
+    texture::Texture,
+};
 
+#[derive(Debug, Clone)]
 pub struct Model {
-    pub cube: Geo,
+    /// root of the scene graph
+    pub scene: Geo,
+    pub camera: Camera,
+    pub lights: Vec<Light>,
+    pub palette: Palette,
 }
 
-impl Model {
-    pub fn new(material_handle: &Handle<NamedMaterials>) -> Model {
-        let named_materials: NamedMaterials = NamedMaterials(material_handle.read().0.clone());
+/// Built from the `SceneDescription` with the same id. Reloads whenever the
+/// description or any asset it refers to changes.
+impl Compound for Model {
+    fn load(cache: AnyCache, id: &SharedString) -> Result<Self, BoxedError> {
+        let description = cache.load::<SceneDescription>(id)?.cloned();
+        let palette = cache.load::<Palette>(&description.palette)?.cloned();
+
+        let mut scene = Geo::new("root", None, Affine3A::IDENTITY);
+        for node in &description.nodes {
+            scene
+                .children
+                .push(load_node(cache, node, &description.palette)?);
+        }
+        Ok(Model {
+            scene,
+            camera: description.camera,
+            lights: description.lights,
+            palette,
+        })
+    }
+}
 
-        // let mesh = obj::parse(Path::new("assets/porygon/model.obj"), named_materials).unwrap();
-        let mesh = obj::parse(Path::new("assets/sphere.obj"), named_materials).unwrap();
-        Model {
-            cube: Geo::new(mesh, Affine3A::IDENTITY),
+fn load_node(cache: AnyCache, node: &NodeDescription, palette_id: &str) -> Result<Geo, BoxedError> {
+    let shape = match &node.mesh {
+        Some(mesh_id) => {
+            let materials_id = node.materials.as_deref();
+            Some(match node.format {
+                MeshFormat::Obj => obj::load_mesh(
+                    cache,
+                    mesh_id,
+                    node.object.as_deref(),
+                    materials_id,
+                    palette_id,
+                )?,
+                MeshFormat::Stl => stl::load_mesh(cache, mesh_id, materials_id, palette_id)?,
+                MeshFormat::Ply => {
+                    ply::load_mesh(cache, mesh_id, materials_id, palette_id, node.vertex_colors)?
+                }
+            })
+        }
+        None => None,
+    };
+    let shape = match (shape, &node.texture) {
+        (Some(mut mesh), Some(texture_id)) => {
+            let palette = cache.load::<Palette>(palette_id)?.read();
+            let texture = cache.load::<Texture>(texture_id)?.cloned();
+            mesh.texture = Some(texture.checked(&palette, texture_id));
+            Some(mesh)
         }
+        (None, Some(_)) => {
+            return Err(format!("node {} has a texture but no mesh", node.name).into())
+        }
+        (shape, None) => shape,
+    };
+    let mut geo = Geo::new(node.name.clone(), shape, node.transform.into());
+    if let Some(gltf_id) = &node.gltf {
+        geo.children.extend(gltf::load_scene(
+            cache,
+            gltf_id,
+            node.materials.as_deref(),
+            palette_id,
+        )?);
+    }
+    for child in &node.children {
+        geo.children.push(load_node(cache, child, palette_id)?);
     }
+    Ok(geo)
 }
 
 /// called every frame
-pub fn draw(buffer: &mut Buffer, model: &Model) {
+pub fn draw(buffer: &mut Buffer, model: &Model) -> RenderStats {
     buffer.clear_screen();
 
-    model.cube.deferred_render(buffer);
-    buffer.finalize_render(&model.cube.shape.materials.0, &model.cube.shape.triangles);
+    let stats = model
+        .scene
+        .deferred_render(buffer, &model.camera, &model.lights);
+    let meshes: Vec<&Mesh> = model.scene.meshes().into_iter().map(|(m, _)| m).collect();
+    buffer.finalize_render(&Textured(DitherMask), &meshes);
+    model.scene.overlay_render(buffer, &model.camera);
+    stats
 }
@@ -0,0 +1,212 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use assets_manager::{loader::Loader, AnyCache, Asset, BoxedError, SharedString};
+use glam::Vec3;
+use serde::Deserialize;
+
+use crate::{
+    color::{Material, Materials, NamedMaterials, Palette},
+    obj::{Location, ObjError},
+};
+
+/// Kd of materials that don't set it, as exported by Blender
+const DEFAULT_DIFFUSE: Vec3 = Vec3::splat(0.8);
+/// Number of shades in generated ramps
+pub const RAMP_SHADES: usize = 9;
+
+/// The materials of an .mtl file. Only the diffuse colors are used.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Mtl(pub HashMap<String, MtlMaterial>);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MtlMaterial {
+    /// `Kd`, with channels from 0 to 1
+    pub diffuse: Vec3,
+}
+
+impl Mtl {
+    /// Materials with shade ramps built from the diffuse colors
+    pub fn to_materials(&self, palette: &Palette) -> NamedMaterials {
+        NamedMaterials(
+            self.0
+                .iter()
+                .map(|(name, material)| {
+                    let material = Material {
+                        shades: shade_ramp(material.diffuse, palette),
+                        curve: None,
+                        shader: None,
+                    };
+                    (name.clone(), material)
+                })
+                .collect(),
+        )
+    }
+}
+
+/// The diffuse color lit at evenly spaced light levels, from dark to full
+/// light, matched to the nearest palette colors
+pub fn shade_ramp(diffuse: Vec3, palette: &Palette) -> Vec<u8> {
+    let levels = RAMP_SHADES as f32;
+    (0..RAMP_SHADES)
+        .map(|i| palette.nearest(diffuse * (i as f32 + 1.) / levels))
+        .collect()
+}
+
+/// A plain gray material, for meshes from files without materials
+pub fn default_material(palette: &Palette) -> Material {
+    Material {
+        shades: shade_ramp(DEFAULT_DIFFUSE, palette),
+        curve: None,
+        shader: None,
+    }
+}
+
+/// The materials in the toml file `materials_id`, or else only the default
+/// material generated with the palette `palette_id`. For formats without
+/// material names, where every face uses the first material.
+pub fn load_materials(
+    cache: AnyCache,
+    materials_id: Option<&str>,
+    palette_id: &str,
+) -> Result<Materials, BoxedError> {
+    let palette = cache.load::<Palette>(palette_id)?.read();
+    match materials_id {
+        Some(materials_id) => {
+            let named_materials = cache.load::<NamedMaterials>(materials_id)?.cloned();
+            if named_materials.0.is_empty() {
+                return Err(format!("{materials_id}.toml has no materials").into());
+            }
+            let mut materials = Materials::from(named_materials.checked(&palette));
+            materials.source = Some(materials_id.to_string());
+            Ok(materials)
+        }
+        None => Ok(Materials::new(
+            vec![default_material(&palette)],
+            vec!["default".to_string()],
+        )),
+    }
+}
+
+pub fn parse(mtl_string: &str) -> Result<Mtl, ObjError> {
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlMaterial)> = None;
+    for (i, text) in mtl_string.lines().enumerate() {
+        let text = text.split('#').next().unwrap_or_default();
+        let syntax = |token: &str, expected: &str| ObjError::Syntax {
+            location: Location {
+                file: None,
+                line: i + 1,
+                token: token.to_string(),
+            },
+            expected: expected.to_string(),
+        };
+        let mut tokens = text.split_whitespace();
+        match tokens.next() {
+            Some("newmtl") => {
+                let name = tokens
+                    .next()
+                    .ok_or_else(|| syntax(text.trim(), "a material name"))?;
+                materials.extend(current.take());
+                current = Some((
+                    name.to_string(),
+                    MtlMaterial {
+                        diffuse: DEFAULT_DIFFUSE,
+                    },
+                ));
+            }
+            Some("Kd") => {
+                let (_, material) = current
+                    .as_mut()
+                    .ok_or_else(|| syntax(text.trim(), "newmtl before Kd"))?;
+                let rgb = tokens
+                    .map(|s| s.parse::<f32>().map_err(|_| syntax(s, "a float")))
+                    .collect::<Result<Vec<_>, _>>()?;
+                material.diffuse = match rgb[..] {
+                    [r, g, b] => Vec3::new(r, g, b),
+                    // a single value is gray
+                    [v] => Vec3::splat(v),
+                    _ => return Err(syntax(text.trim(), "1 or 3 floats")),
+                };
+            }
+            // other colors, textures and illumination models have no use yet
+            _ => {}
+        }
+    }
+    materials.extend(current);
+    Ok(Mtl(materials))
+}
+
+pub struct MtlLoader;
+
+impl Loader<Mtl> for MtlLoader {
+    fn load(content: Cow<[u8]>, _ext: &str) -> Result<Mtl, BoxedError> {
+        let mtl_string = std::str::from_utf8(&content)
+            .map_err(|source| ObjError::Encoding { file: None, source })?;
+        Ok(parse(mtl_string)?)
+    }
+}
+
+impl Asset for Mtl {
+    const EXTENSION: &'static str = "mtl";
+    type Loader = MtlLoader;
+}
+
+/// Hand-written materials that replace the generated ones of the .mtl file
+/// with the same id. The file is optional.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(transparent)]
+pub struct MaterialOverrides(pub NamedMaterials);
+
+impl Asset for MaterialOverrides {
+    const EXTENSION: &'static str = "toml";
+    type Loader = assets_manager::loader::TomlLoader;
+
+    fn default_value(_id: &SharedString, error: BoxedError) -> Result<Self, BoxedError> {
+        match error.downcast_ref::<std::io::Error>() {
+            Some(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            _ => Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grayscale_palette() -> Palette {
+        let mut colors = [0; 32];
+        for (i, color) in colors.iter_mut().enumerate() {
+            let v = (i * 255 / 31) as u32;
+            *color = (v << 24) | (v << 16) | (v << 8) | 0xFF;
+        }
+        Palette {
+            colors: colors.to_vec(),
+        }
+    }
+
+    #[test]
+    fn parse_materials() {
+        let mtl = parse(
+            "
+            # comment
+            newmtl red
+              Kd 1.00 0.00 0.00
+              Ks 0.5 0.5 0.5
+
+            newmtl plain
+            ",
+        )
+        .unwrap();
+        assert_eq!(Vec3::X, mtl.0["red"].diffuse);
+        assert_eq!(DEFAULT_DIFFUSE, mtl.0["plain"].diffuse);
+        assert!(parse("Kd 1 1 1").is_err());
+    }
+
+    #[test]
+    fn ramp_gets_brighter() {
+        let palette = grayscale_palette();
+        let shades = shade_ramp(Vec3::ONE, &palette);
+        assert_eq!(31, shades[8]);
+        assert!(shades.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
@@ -1,171 +1,851 @@
-use std::{collections::HashMap, fs::read_to_string, io::Error, path::Path};
+use std::{
+    borrow::Cow, collections::HashMap, fmt, fs::read_to_string, io, ops::Range, path::Path,
+    str::Utf8Error,
+};
 
-use glam::Vec3;
+use assets_manager::{loader::Loader, AnyCache, Asset, BoxedError, Compound, SharedString};
+use glam::{Vec2, Vec3};
 
 use crate::{
-    color::{Materials, NamedMaterials},
-    geo::{IndexedTriangle, Mesh},
+    color::{NamedMaterials, Palette},
+    geo::{self, CullMode, IndexedTriangle, Mesh, RenderMode, Winding},
+    mtl::{MaterialOverrides, Mtl},
 };
 
+/// Where in an .obj file something went wrong
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    /// path or asset id, if known
+    pub file: Option<String>,
+    /// starting at 1
+    pub line: usize,
+    /// the offending token, or the whole statement
+    pub token: String,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file = self.file.as_deref().unwrap_or("<obj>");
+        write!(f, "{file}:{}: `{}`", self.line, self.token)
+    }
+}
+
+/// The kinds of elements that faces and lines refer to by index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Element {
+    Vertex,
+    TextureCoordinate,
+    Normal,
+}
+
+impl fmt::Display for Element {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Element::Vertex => "vertex",
+            Element::TextureCoordinate => "texture coordinate",
+            Element::Normal => "normal",
+        })
+    }
+}
+
 #[derive(Debug)]
-#[allow(dead_code)]
-enum Line {
-    ObjectName(String),
-    Vertex(f32, f32, f32),
-    Face(Vec<usize>, String), // &str is a material name
-    UseMtl(String, String),   // Color might be removed later // TODO: clean up: both String?
-}
-pub fn parse(path: &Path, named_materials: NamedMaterials) -> Result<Mesh, Error> {
-    let obj_string = read_to_string(path)?;
-
-    let materials: Materials = named_materials.clone().into();
-
-    // sort named materials by name
-    let mut unsorted_materials: Vec<_> = named_materials.0.clone().into_keys().collect();
-    unsorted_materials.sort_by(|x, y| x.cmp(&(y)));
-    let material_references: HashMap<String, usize> = unsorted_materials
-        .into_iter()
-        .enumerate()
-        .map(|(i, v)| (v, i))
-        .collect();
+pub enum ObjError {
+    Io {
+        file: Option<String>,
+        source: io::Error,
+    },
+    /// the file is not UTF-8 text
+    Encoding {
+        file: Option<String>,
+        source: Utf8Error,
+    },
+    /// a statement that does not follow the OBJ format
+    Syntax {
+        location: Location,
+        expected: String,
+    },
+    /// a face or line refers to an element that is not defined before it
+    BadIndex {
+        location: Location,
+        element: Element,
+        /// how many of `element` were defined before the statement
+        count: usize,
+    },
+    /// a `usemtl` names a material missing from the mesh's materials
+    MissingMaterial { location: Location },
+    /// the file has faces, but there are no materials to draw them with
+    NoMaterials { file: Option<String> },
+}
+
+impl ObjError {
+    /// Sets the file of the error, for errors from parsing a string
+    pub fn in_file(mut self, name: impl Into<String>) -> ObjError {
+        let name = Some(name.into());
+        match &mut self {
+            ObjError::Io { file, .. }
+            | ObjError::Encoding { file, .. }
+            | ObjError::NoMaterials { file } => *file = name,
+            ObjError::Syntax { location, .. }
+            | ObjError::BadIndex { location, .. }
+            | ObjError::MissingMaterial { location } => location.file = name,
+        }
+        self
+    }
+}
 
-    let mut current_material_name = "".to_string();
-
-    let data: Vec<_> = obj_string
-        .lines()
-        .filter_map(|line| {
-            let mut tokens = line.split_whitespace();
-            match tokens.next() {
-                Some("o") => Some(
-                    tokens
-                        .next()
-                        .ok_or(Error::other("Missing object name"))
-                        .map(|name| Line::ObjectName(name.to_string())),
-                ),
-                Some("v") => Some(
-                    tokens
-                        .map(|s| {
-                            s.parse().map_err(|_| {
-                                Error::other(std::format!("Expected Float; found {s}"))
-                            })
-                        })
-                        .collect::<Result<Vec<_>, _>>()
-                        .and_then(|vs| match vs.len() {
-                            3 => Ok(Line::Vertex(vs[0], vs[1], vs[2])),
-                            _ => Err(Error::other(std::format!(
-                                "Expected 3 Floats; found {vs:?}"
-                            ))),
-                        }),
-                ),
-                Some("f") => Some(
-                    tokens
-                        .map(|s| {
-                            s.split("/")
-                                .next()
-                                .ok_or(Error::other("Missing vertex index"))
-                                .and_then(|vertex_index| {
-                                    vertex_index.parse::<usize>().map_err(|_| {
-                                        Error::other(std::format!(
-                                            "Expected usize index; found {vertex_index}"
-                                        ))
-                                    })
-                                })
-                                .and_then(|vertex_index| {
-                                    vertex_index.checked_sub(1).ok_or(Error::other(
-                                        "Expected non-zero vertex index; obj indices start at 1!",
-                                    ))
-                                })
-                        })
-                        .collect::<Result<Vec<_>, _>>()
-                        .and_then(|fs| match fs.len() {
-                            3.. => Ok(Line::Face(fs, current_material_name.clone())),
-                            _ => Err(Error::other(std::format!(
-                                "Expected 3 or more vertex indices, found {fs:?}"
-                            ))),
-                        }),
-                ),
-                Some("g") => None,
-                Some("vn") => None,
-                Some("vt") => None,
-                Some("#") => None,
-                Some("usemtl") => Some(
-                    tokens
-                        .next()
-                        .ok_or(Error::other("Missing object name"))
-                        .map(|name| {
-                            current_material_name = name.to_string();
-                            //                                match name {
-                            //                                "mat4" => Color::Cyan2,
-                            //                                "mat8" => Color::Red,
-                            //                                "mat21" => Color::White,
-                            //                                "mat23" => Color::Black,
-                            //                                _ => Color::Pink0,
-                            //                            };
-                            Line::UseMtl(name.to_string(), current_material_name.clone())
-                        }),
-                ),
-                Some("mtllib") => None, // Not sure what this is!
-                None => None,
-                _ => todo!("{:?}", tokens),
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file = |file: &Option<String>| file.clone().unwrap_or("<obj>".to_string());
+        match self {
+            ObjError::Io { file: name, source } => write!(f, "{}: {source}", file(name)),
+            ObjError::Encoding { file: name, source } => {
+                write!(f, "{}: not UTF-8 text: {source}", file(name))
             }
-        })
-        .collect::<Result<Vec<_>, _>>()?;
+            ObjError::Syntax { location, expected } => {
+                write!(f, "{location}: expected {expected}")
+            }
+            ObjError::BadIndex {
+                location,
+                element,
+                count,
+            } => write!(
+                f,
+                "{location}: {element} index out of range, {count} defined so far"
+            ),
+            ObjError::MissingMaterial { location } => {
+                write!(f, "{location}: material not found")
+            }
+            ObjError::NoMaterials { file: name } => {
+                write!(f, "{}: no materials for the faces", file(name))
+            }
+        }
+    }
+}
 
-    let vertices = data
-        .iter()
-        .filter_map(|line| match line {
-            Line::Vertex(v1, v2, v3) => Some(Vec3::new(*v1, *v2, *v3)),
+impl std::error::Error for ObjError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ObjError::Io { source, .. } => Some(source),
+            ObjError::Encoding { source, .. } => Some(source),
             _ => None,
-        })
-        .collect();
+        }
+    }
+}
 
-    let triangles = data
-        .iter()
-        .filter_map(|line| match line {
-            Line::Face(fs, material_name) => match fs.len() {
-                0..=2 => panic!(),
-                3 => Some(vec![IndexedTriangle {
-                    index: (fs[0], fs[1], fs[2]),
-                    material_index: get_material(&material_references, material_name),
-                }]),
-                4 => Some(vec![
-                    IndexedTriangle {
-                        index: (fs[0], fs[1], fs[2]),
-                        material_index: get_material(&material_references, material_name),
-                    },
-                    IndexedTriangle {
-                        index: (fs[2], fs[3], fs[0]),
-                        material_index: get_material(&material_references, material_name),
+/// The contents of an .obj file before materials are resolved.
+/// `material_index` of the triangles indexes into `material_names`.
+#[derive(Debug, Clone)]
+pub struct ObjData {
+    pub vertices: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub uvs: Vec<Vec2>,
+    pub triangles: Vec<IndexedTriangle>,
+    /// segments of `l` statements, as indices into `vertices`
+    pub lines: Vec<(usize, usize)>,
+    /// consecutive runs of triangles under the same `o` and `g` statements
+    pub groups: Vec<ObjGroup>,
+    /// in order of first use. Faces before any `usemtl` use the material named ""
+    pub material_names: Vec<String>,
+    /// the line each of `material_names` is first used on
+    pub material_lines: Vec<usize>,
+    /// the file named by `mtllib`, relative to the .obj file
+    pub mtllib: Option<String>,
+}
+
+/// Triangles of an `ObjData` that belong to a named object or group
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjGroup {
+    /// set by `o`
+    pub object: Option<String>,
+    /// set by `g`
+    pub group: Option<String>,
+    pub triangles: Range<usize>,
+}
+
+impl ObjData {
+    /// The triangles of every object or group called `name`, as their own
+    /// `ObjData`. Vertex data is kept whole, but lines are left out.
+    pub fn sub_mesh(&self, name: &str) -> Option<ObjData> {
+        let name = Some(name);
+        let groups: Vec<&ObjGroup> = self
+            .groups
+            .iter()
+            .filter(|g| g.object.as_deref() == name || g.group.as_deref() == name)
+            .collect();
+        if groups.is_empty() {
+            return None;
+        }
+        let mut sub_mesh = ObjData {
+            triangles: Vec::new(),
+            lines: Vec::new(),
+            groups: Vec::new(),
+            ..self.clone()
+        };
+        for group in groups {
+            let start = sub_mesh.triangles.len();
+            sub_mesh
+                .triangles
+                .extend_from_slice(&self.triangles[group.triangles.clone()]);
+            sub_mesh.groups.push(ObjGroup {
+                triangles: start..sub_mesh.triangles.len(),
+                ..group.clone()
+            });
+        }
+        Some(sub_mesh)
+    }
+
+    /// Fails if a `usemtl` names a material that isn't in `named_materials`.
+    /// Faces before any `usemtl` get the first material.
+    pub fn into_mesh(self, named_materials: NamedMaterials) -> Result<Mesh, ObjError> {
+        if named_materials.0.is_empty() && !self.triangles.is_empty() {
+            return Err(ObjError::NoMaterials { file: None });
+        }
+        // sort named materials by name
+        let mut sorted_names: Vec<_> = named_materials.0.keys().cloned().collect();
+        sorted_names.sort();
+        let material_references: HashMap<String, usize> = sorted_names
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| (v, i))
+            .collect();
+        let material_indices = self
+            .material_names
+            .iter()
+            .zip(&self.material_lines)
+            .map(|(name, &line)| match material_references.get(name) {
+                Some(&i) => Ok(i),
+                None if name.is_empty() => Ok(0),
+                None => Err(ObjError::MissingMaterial {
+                    location: Location {
+                        file: None,
+                        line,
+                        token: name.clone(),
                     },
-                ]),
-                _ => Some(
-                    fs[1..]
-                        .windows(2)
-                        .map(|window_f| IndexedTriangle {
-                            index: (fs[0], window_f[0], window_f[1]),
-                            material_index: get_material(&material_references, material_name),
-                        })
-                        .collect::<Vec<_>>(),
-                ),
-            },
-            _ => None,
+                }),
+            })
+            .collect::<Result<Vec<usize>, _>>()?;
+
+        Ok(Mesh {
+            materials: named_materials.into(),
+            triangles: self
+                .triangles
+                .into_iter()
+                .map(|triangle| IndexedTriangle {
+                    material_index: material_indices[triangle.material_index],
+                    ..triangle
+                })
+                .collect(),
+            vertices: self.vertices,
+            normals: self.normals,
+            uvs: self.uvs,
+            lines: self.lines,
+            texture: None,
+            cull_mode: CullMode::default(),
+            winding: Winding::default(),
+            render_mode: RenderMode::default(),
         })
-        .flatten()
+    }
+}
+
+pub struct ObjLoader;
+
+impl Loader<ObjData> for ObjLoader {
+    fn load(content: Cow<[u8]>, _ext: &str) -> Result<ObjData, BoxedError> {
+        let obj_string = std::str::from_utf8(&content)
+            .map_err(|source| ObjError::Encoding { file: None, source })?;
+        Ok(parse_data(obj_string)?)
+    }
+}
+
+impl Asset for ObjData {
+    const EXTENSION: &'static str = "obj";
+    type Loader = ObjLoader;
+}
+
+/// Loads `<id>.obj` with the materials of its `mtllib`, see `load_mesh`.
+/// Shade ramps are generated with the palette `"palette"`.
+impl Compound for Mesh {
+    fn load(cache: AnyCache, id: &SharedString) -> Result<Self, BoxedError> {
+        load_mesh(cache, id, None, None, "palette")
+    }
+}
+
+/// Loads the .obj file `id` from the cache, or only its `object` or group if given.
+///
+/// Materials are read from the toml file `materials_id`, or else from the
+/// `mtllib` of the .obj file. Shade ramps for those are generated from the
+/// diffuse colors with the palette `palette_id`, unless a toml file next to the
+/// .mtl file sets them by hand: `mtllib materials.mtl` in `porygon/model.obj`
+/// reads `porygon/materials.mtl` and `porygon/materials.toml`.
+pub fn load_mesh(
+    cache: AnyCache,
+    id: &str,
+    object: Option<&str>,
+    materials_id: Option<&str>,
+    palette_id: &str,
+) -> Result<Mesh, BoxedError> {
+    let data = cache.load::<ObjData>(id)?.cloned();
+    let data = match object {
+        Some(object) => data
+            .sub_mesh(object)
+            .ok_or_else(|| format!("{id}.obj has no object or group called {object}"))?,
+        None => data,
+    };
+    let palette = cache.load::<Palette>(palette_id)?.read();
+    // the toml file that edited materials are saved to
+    let source;
+    let named_materials = match (materials_id, &data.mtllib) {
+        (Some(materials_id), _) => {
+            source = materials_id.to_string();
+            cache.load::<NamedMaterials>(materials_id)?.cloned()
+        }
+        (None, Some(mtllib)) => {
+            let mtl_id = sibling_id(id, mtllib)?;
+            source = mtl_id.clone();
+            let mut named_materials = cache.load::<Mtl>(&mtl_id)?.read().to_materials(&palette);
+            let overrides = cache.load::<MaterialOverrides>(&mtl_id)?.cloned();
+            named_materials.0.extend(overrides.0 .0);
+            named_materials
+        }
+        (None, None) => {
+            return Err(ObjError::NoMaterials {
+                file: Some(format!("{id}.obj")),
+            }
+            .into())
+        }
+    };
+    let mut mesh = data
+        .into_mesh(named_materials.checked(&palette))
+        .map_err(|e| e.in_file(format!("{id}.obj")))?;
+    mesh.materials.source = Some(source);
+    Ok(mesh)
+}
+
+/// The asset id of `path` relative to the asset `id`, without its extension.
+/// Ids separate directories with dots, so `path` can't have any others.
+pub(crate) fn sibling_id(id: &str, path: &str) -> Result<String, String> {
+    let (directories, file) = path.rsplit_once('/').unwrap_or(("", path));
+    let stem = file.rsplit_once('.').map_or(file, |(stem, _)| stem);
+    let mut parts: Vec<&str> = directories
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
         .collect();
+    parts.push(stem);
+    if let Some(part) = parts
+        .iter()
+        .find(|part| part.is_empty() || part.contains('.'))
+    {
+        return Err(format!(
+            "can't load {path}: `{part}` can't be part of an asset id, rename it without dots"
+        ));
+    }
+    let relative = parts.join(".");
+    Ok(match id.rsplit_once('.') {
+        Some((directory, _)) => format!("{directory}.{relative}"),
+        None => relative,
+    })
+}
 
-    Ok(Mesh {
-        materials,
-        triangles,
+/// One corner of a face: `v`, `v/vt`, `v//vn` or `v/vt/vn`, converted to 0-based indices
+#[derive(Debug, Clone, Copy)]
+struct FaceVertex {
+    vertex: usize,
+    texture: Option<usize>,
+    normal: Option<usize>,
+}
+
+/// Which faces share generated normals
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Smoothing {
+    /// set by `s 1`, `s 2`, ... Faces before any `s` statement are in group 0,
+    /// so files without smoothing groups are smooth all over.
+    Group(u32),
+    /// set by `s off` or `s 0`. Holds the index of the face, which keeps its own normal.
+    Off(usize),
+}
+
+#[derive(Debug)]
+struct Face {
+    corners: Vec<FaceVertex>,
+    /// index into `ObjData::material_names`
+    material: usize,
+    smoothing: Smoothing,
+}
+
+/// Counts of the `v`, `vt` and `vn` statements so far, which negative indices count back from
+#[derive(Debug, Clone, Copy)]
+struct IndexCounts {
+    vertices: usize,
+    uvs: usize,
+    normals: usize,
+}
+
+/// The line being parsed, for error locations
+#[derive(Debug, Clone, Copy)]
+struct Statement<'a> {
+    line: usize,
+    text: &'a str,
+}
+
+impl Statement<'_> {
+    fn location(&self, token: &str) -> Location {
+        Location {
+            file: None,
+            line: self.line,
+            token: token.to_string(),
+        }
+    }
+
+    fn syntax(&self, token: &str, expected: impl Into<String>) -> ObjError {
+        ObjError::Syntax {
+            location: self.location(token),
+            expected: expected.into(),
+        }
+    }
+
+    /// for errors about the statement as a whole
+    fn syntax_all(&self, expected: impl Into<String>) -> ObjError {
+        self.syntax(self.text.trim(), expected)
+    }
+}
+
+pub fn parse(path: &Path, named_materials: NamedMaterials) -> Result<Mesh, ObjError> {
+    let file = path.display().to_string();
+    let obj_string = read_to_string(path).map_err(|source| ObjError::Io {
+        file: Some(file.clone()),
+        source,
+    })?;
+    parse_str(&obj_string, named_materials).map_err(|e| e.in_file(file))
+}
+
+/// Parses the contents of an .obj file
+pub fn parse_str(obj_string: &str, named_materials: NamedMaterials) -> Result<Mesh, ObjError> {
+    parse_data(obj_string)?.into_mesh(named_materials)
+}
+
+/// Faces and lines can only refer to elements defined above them. Indices are
+/// checked, so every index in the result is in range.
+pub fn parse_data(obj_string: &str) -> Result<ObjData, ObjError> {
+    let mut vertices: Vec<Vec3> = Vec::new();
+    let mut normals: Vec<Vec3> = Vec::new();
+    let mut uvs: Vec<Vec2> = Vec::new();
+    let mut faces: Vec<Face> = Vec::new();
+    let mut lines: Vec<(usize, usize)> = Vec::new();
+    let mut groups: Vec<ObjGroup> = Vec::new();
+    let mut material_names: Vec<String> = Vec::new();
+    let mut material_lines: Vec<usize> = Vec::new();
+    let mut mtllib = None;
+
+    let mut current_material = None;
+    // `None` after `s off`
+    let mut smoothing_group = Some(0);
+    let mut object: Option<String> = None;
+    let mut group: Option<String> = None;
+    // the triangle that starts the current object/group
+    let mut group_start = 0;
+    let mut triangle_count = 0;
+
+    for (i, text) in obj_string.lines().enumerate() {
+        // comments can also follow a statement
+        let text = text.split('#').next().unwrap_or_default();
+        let statement = Statement { line: i + 1, text };
+        let mut tokens = text.split_whitespace();
+        let Some(keyword) = tokens.next() else {
+            continue;
+        };
+        let counts = IndexCounts {
+            vertices: vertices.len(),
+            uvs: uvs.len(),
+            normals: normals.len(),
+        };
+        match keyword {
+            // an optional w, or vertex colors some exporters add, are ignored
+            "v" => {
+                let vs = parse_floats(tokens, 3, 7, statement)?;
+                vertices.push(Vec3::new(vs[0], vs[1], vs[2]));
+            }
+            "vn" => {
+                let vs = parse_floats(tokens, 3, 3, statement)?;
+                normals.push(Vec3::new(vs[0], vs[1], vs[2]).normalize_or_zero());
+            }
+            // the optional third coordinate is for 3D textures
+            "vt" => {
+                let vs = parse_floats(tokens, 1, 3, statement)?;
+                uvs.push(Vec2::new(vs[0], vs.get(1).copied().unwrap_or_default()));
+            }
+            // free-form geometry is not supported, so parameter space vertices are unused
+            "vp" => {
+                parse_floats(tokens, 1, 3, statement)?;
+            }
+            "f" => {
+                let corners = tokens
+                    .map(|token| parse_face_vertex(token, counts, statement))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if corners.len() < 3 {
+                    return Err(statement.syntax_all("3 or more vertices"));
+                }
+                let material = *current_material.get_or_insert_with(|| {
+                    material_index(&mut material_names, &mut material_lines, "", statement)
+                });
+                let smoothing = match smoothing_group {
+                    Some(group) => Smoothing::Group(group),
+                    None => Smoothing::Off(faces.len()),
+                };
+                triangle_count += corners.len() - 2;
+                faces.push(Face {
+                    corners,
+                    material,
+                    smoothing,
+                });
+            }
+            // a polyline, stored as separate segments
+            "l" => {
+                let points = tokens
+                    .map(|token| parse_face_vertex(token, counts, statement).map(|f| f.vertex))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if points.len() < 2 {
+                    return Err(statement.syntax_all("2 or more vertices"));
+                }
+                lines.extend(points.windows(2).map(|pair| (pair[0], pair[1])));
+            }
+            "o" | "g" => {
+                push_group(&mut groups, &object, &group, group_start..triangle_count);
+                group_start = triangle_count;
+                // names can contain spaces. `g` can list several groups, which
+                // are treated as one
+                let name = tokens.collect::<Vec<_>>().join(" ");
+                let name = (!name.is_empty()).then_some(name);
+                if keyword == "o" {
+                    object = name;
+                    group = None;
+                } else {
+                    group = name;
+                }
+            }
+            "s" => {
+                smoothing_group = match tokens.next() {
+                    Some("off" | "0") => None,
+                    Some(group) => Some(
+                        group
+                            .parse()
+                            .map_err(|_| statement.syntax(group, "a smoothing group or off"))?,
+                    ),
+                    None => return Err(statement.syntax_all("a smoothing group or off")),
+                }
+            }
+            "usemtl" => {
+                let name = tokens
+                    .next()
+                    .ok_or_else(|| statement.syntax_all("a material name"))?;
+                current_material = Some(material_index(
+                    &mut material_names,
+                    &mut material_lines,
+                    name,
+                    statement,
+                ));
+            }
+            "mtllib" => {
+                // only the first library is used
+                if mtllib.is_none() {
+                    mtllib = tokens.next().map(str::to_string);
+                }
+            }
+            _ => log::warn!(
+                "OBJ Parsing: Skipping unsupported statement on line {}: {keyword}",
+                statement.line
+            ),
+        }
+    }
+    push_group(&mut groups, &object, &group, group_start..triangle_count);
+
+    // corners without a `vn` get a normal averaged over the faces around their
+    // vertex in the same smoothing group, stored after the file's normals
+    let mut generated: HashMap<(usize, Smoothing), usize> = HashMap::new();
+    let mut smooth_vertices: Vec<Vec3> = Vec::new();
+    let mut smooth_faces = Vec::new();
+    for face in &faces {
+        let mut smooth_index = |f: &FaceVertex| {
+            *generated
+                .entry((f.vertex, face.smoothing))
+                .or_insert_with(|| {
+                    smooth_vertices.push(vertices[f.vertex]);
+                    smooth_vertices.len() - 1
+                })
+        };
+        let first = smooth_index(&face.corners[0]);
+        for pair in face.corners[1..].windows(2) {
+            smooth_faces.push((first, smooth_index(&pair[0]), smooth_index(&pair[1])));
+        }
+    }
+    let generated_offset = normals.len();
+    if faces
+        .iter()
+        .flat_map(|f| &f.corners)
+        .any(|f| f.normal.is_none())
+    {
+        normals.extend(geo::vertex_normals(&smooth_vertices, &smooth_faces));
+    }
+
+    // fan triangulation of each face
+    let mut triangles = Vec::with_capacity(triangle_count);
+    for face in &faces {
+        let normal_index = |f: FaceVertex| {
+            f.normal
+                .unwrap_or_else(|| generated_offset + generated[&(f.vertex, face.smoothing)])
+        };
+        let first = face.corners[0];
+        for pair in face.corners[1..].windows(2) {
+            let f = [first, pair[0], pair[1]];
+            triangles.push(IndexedTriangle {
+                index: (f[0].vertex, f[1].vertex, f[2].vertex),
+                normal_index: (normal_index(f[0]), normal_index(f[1]), normal_index(f[2])),
+                uv_index: match (f[0].texture, f[1].texture, f[2].texture) {
+                    (Some(u1), Some(u2), Some(u3)) => Some((u1, u2, u3)),
+                    _ => None,
+                },
+                material_index: face.material,
+            });
+        }
+    }
+
+    Ok(ObjData {
         vertices,
+        normals,
+        uvs,
+        triangles,
+        lines,
+        groups,
+        material_names,
+        material_lines,
+        mtllib,
     })
 }
 
-fn get_material(map: &HashMap<String, usize>, name: &str) -> usize {
-    // TODO this could be handled better. Currently I think porygon's mesh is
-    // hardcoded. But I wanted our other .obj files to run without fussing with
-    // making a materials.toml file for them for now.
-    *map.get(name).unwrap_or_else(|| {
-        println!("WARNING: OBJ Parsing: Could not find material {name}. Using default.");
-        &0
+fn material_index(
+    material_names: &mut Vec<String>,
+    material_lines: &mut Vec<usize>,
+    name: &str,
+    statement: Statement,
+) -> usize {
+    match material_names.iter().position(|n| n == name) {
+        Some(i) => i,
+        None => {
+            material_names.push(name.to_string());
+            material_lines.push(statement.line);
+            material_names.len() - 1
+        }
+    }
+}
+
+fn push_group(
+    groups: &mut Vec<ObjGroup>,
+    object: &Option<String>,
+    group: &Option<String>,
+    triangles: Range<usize>,
+) {
+    if !triangles.is_empty() {
+        groups.push(ObjGroup {
+            object: object.clone(),
+            group: group.clone(),
+            triangles,
+        });
+    }
+}
+
+fn parse_floats<'a>(
+    tokens: impl Iterator<Item = &'a str>,
+    min: usize,
+    max: usize,
+    statement: Statement,
+) -> Result<Vec<f32>, ObjError> {
+    let vs = tokens
+        .map(|s| s.parse().map_err(|_| statement.syntax(s, "a float")))
+        .collect::<Result<Vec<f32>, _>>()?;
+    match vs.len() {
+        n if (min..=max).contains(&n) => Ok(vs),
+        _ if min == max => Err(statement.syntax_all(std::format!("{min} floats"))),
+        _ => Err(statement.syntax_all(std::format!("{min} to {max} floats"))),
+    }
+}
+
+/// Converts 1-based indices, or negative ones counting back from the
+/// `count` elements so far, to 0-based indices. `token` is the whole face vertex.
+fn parse_index(
+    index: &str,
+    token: &str,
+    element: Element,
+    count: usize,
+    statement: Statement,
+) -> Result<usize, ObjError> {
+    let index = index
+        .parse::<isize>()
+        .map_err(|_| statement.syntax(token, std::format!("an integer {element} index")))?;
+    let resolved = match index {
+        1.. => Some(index as usize - 1),
+        0 => {
+            return Err(statement.syntax(
+                token,
+                std::format!("a non-zero {element} index; obj indices start at 1"),
+            ))
+        }
+        _ => count.checked_add_signed(index),
+    };
+    resolved
+        .filter(|&i| i < count)
+        .ok_or_else(|| ObjError::BadIndex {
+            location: statement.location(token),
+            element,
+            count,
+        })
+}
+
+fn parse_face_vertex(
+    token: &str,
+    counts: IndexCounts,
+    statement: Statement,
+) -> Result<FaceVertex, ObjError> {
+    let mut indices = token.split("/");
+    // split always yields at least one item
+    let vertex = parse_index(
+        indices.next().unwrap_or_default(),
+        token,
+        Element::Vertex,
+        counts.vertices,
+        statement,
+    )?;
+    let mut optional_index = |element, count| {
+        indices
+            .next()
+            .filter(|s| !s.is_empty())
+            .map(|index| parse_index(index, token, element, count, statement))
+            .transpose()
+    };
+    let texture = optional_index(Element::TextureCoordinate, counts.uvs)?;
+    let normal = optional_index(Element::Normal, counts.normals)?;
+    Ok(FaceVertex {
+        vertex,
+        texture,
+        normal,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUAD: &str = "
+        v 0 0 0
+        v 1 0 0
+        v 1 1 0
+        v 0 1 0
+        ";
+
+    #[test]
+    fn negative_indices() {
+        let data = parse_data(&format!("{QUAD} f -4 -3 -2 -1")).unwrap();
+        let absolute = parse_data(&format!("{QUAD} f 1 2 3 4")).unwrap();
+        assert_eq!(2, data.triangles.len());
+        for (a, b) in data.triangles.iter().zip(&absolute.triangles) {
+            assert_eq!(a.index, b.index);
+        }
+        assert!(parse_data(&format!("{QUAD} f -5 1 2")).is_err());
+    }
+
+    #[test]
+    fn objects_and_groups() {
+        let data = parse_data(&format!(
+            "{QUAD}
+            o first
+            f 1 2 3
+            g part
+            f 1 3 4
+            o second
+            f 1 2 3 4
+            "
+        ))
+        .unwrap();
+        assert_eq!(3, data.groups.len());
+        assert_eq!(2, data.sub_mesh("first").unwrap().triangles.len());
+        assert_eq!(1, data.sub_mesh("part").unwrap().triangles.len());
+        assert_eq!(2, data.sub_mesh("second").unwrap().triangles.len());
+        assert!(data.sub_mesh("third").is_none());
+    }
+
+    #[test]
+    fn smoothing_groups() {
+        // two faces meeting at a right angle along the edge 1-2
+        let hinge = "
+            v 0 0 0
+            v 1 0 0
+            v 1 0 -1
+            v 0 1 0
+            v 1 1 0
+            ";
+        let smooth = parse_data(&format!("{hinge} s 1 \n f 1 2 3 \n f 1 2 5 4")).unwrap();
+        let flat = parse_data(&format!("{hinge} s off \n f 1 2 3 \n f 1 2 5 4")).unwrap();
+
+        let shared_normal = |data: &ObjData| {
+            data.normals[data.triangles[0].normal_index.0]
+                == data.normals[data.triangles[1].normal_index.0]
+        };
+        assert!(shared_normal(&smooth));
+        assert!(!shared_normal(&flat));
+    }
+
+    #[test]
+    fn lines_and_unknown_statements() {
+        let data = parse_data(&format!("{QUAD} l 1 2 3 \n vp 0.5 \n curv 0 1 1 2")).unwrap();
+        assert_eq!(vec![(0, 1), (1, 2)], data.lines);
+        assert!(data.triangles.is_empty());
+    }
+
+    #[test]
+    fn errors_have_locations() {
+        let err = parse_data(&format!("{QUAD} f 1 2 3/1")).unwrap_err();
+        let ObjError::BadIndex {
+            location,
+            element,
+            count,
+        } = err
+        else {
+            panic!("{err}");
+        };
+        assert_eq!((6, "3/1"), (location.line, location.token.as_str()));
+        assert_eq!((Element::TextureCoordinate, 0), (element, count));
+
+        let err = parse_data("v 0 0 zero").unwrap_err().in_file("a.obj");
+        assert_eq!("a.obj:1: `zero`: expected a float", err.to_string());
+    }
+
+    #[test]
+    fn missing_material() {
+        let data = parse_data(&format!("{QUAD} usemtl red \n f 1 2 3")).unwrap();
+        let err = data.into_mesh(NamedMaterials::default()).unwrap_err();
+        assert!(matches!(err, ObjError::NoMaterials { .. }));
+
+        let data = parse_data(&format!("{QUAD} usemtl red \n f 1 2 3")).unwrap();
+        let materials = NamedMaterials(HashMap::from([(
+            "blue".to_string(),
+            crate::color::Material {
+                shades: vec![0; 9],
+                curve: None,
+                shader: None,
+            },
+        )]));
+        let err = data.into_mesh(materials).unwrap_err();
+        assert!(matches!(err, ObjError::MissingMaterial { location } if location.line == 6));
+    }
+
+    #[test]
+    fn sibling_ids() {
+        assert_eq!(
+            "porygon.materials",
+            sibling_id("porygon.model", "materials.mtl").unwrap()
+        );
+        assert_eq!("materials", sibling_id("model", "./materials.mtl").unwrap());
+        assert_eq!("a.b.c", sibling_id("a.model", "b/c.mtl").unwrap());
+        // read by the cache as the path `dir/materials/v2`
+        assert!(sibling_id("dir.model", "materials.v2.mtl").is_err());
+        assert!(sibling_id("dir.model", "../materials.mtl").is_err());
+    }
+}
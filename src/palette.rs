@@ -0,0 +1,245 @@
+use std::{borrow::Cow, fmt, str::Utf8Error};
+
+use assets_manager::{loader::Loader, Asset, BoxedError};
+use serde::Deserialize;
+
+use crate::{
+    color::Palette,
+    constants::{MAX_PALETTE_SIZE, MIN_PALETTE_SIZE},
+};
+
+#[derive(Debug)]
+pub enum PaletteError {
+    Encoding(Utf8Error),
+    Toml(toml::de::Error),
+    Syntax {
+        line: usize,
+        token: String,
+        expected: String,
+    },
+    /// fewer colors than needed or more than u8 indices can refer to
+    ColorCount {
+        count: usize,
+    },
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaletteError::Encoding(e) => write!(f, "palette is not valid UTF-8: {e}"),
+            PaletteError::Toml(e) => write!(f, "{e}"),
+            PaletteError::Syntax {
+                line,
+                token,
+                expected,
+            } => write!(f, "line {line}: `{token}`: expected {expected}"),
+            PaletteError::ColorCount { count } => write!(
+                f,
+                "palette has {count} colors, but needs {MIN_PALETTE_SIZE} to {MAX_PALETTE_SIZE}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PaletteError::Encoding(e) => Some(e),
+            PaletteError::Toml(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The layout of `assets/palette.toml`
+#[derive(Deserialize)]
+struct PaletteToml {
+    colors: Vec<u32>,
+}
+
+/// Reads the colors of a palette file as 0xRRGGBBAA. `extension` picks the format:
+///
+/// - `toml`: a `colors` array
+/// - `txt`: paint.net, AARRGGBB hex per line, `;` comments
+/// - `gpl`: GIMP, decimal `R G B name` per line after the header
+/// - `hex`: Lospec, RRGGBB hex per line
+/// - `pal`: JASC, `JASC-PAL`, version and count, then decimal `R G B` lines
+pub fn parse_colors(palette_string: &str, extension: &str) -> Result<Vec<u32>, PaletteError> {
+    if extension == "toml" {
+        let palette: PaletteToml = toml::from_str(palette_string).map_err(PaletteError::Toml)?;
+        return Ok(palette.colors);
+    }
+
+    let mut colors = Vec::new();
+    for (i, text) in palette_string.lines().enumerate() {
+        let line = i + 1;
+        let text = text.trim();
+        let syntax = |token: &str, expected: &str| PaletteError::Syntax {
+            line,
+            token: token.to_string(),
+            expected: expected.to_string(),
+        };
+        let hex = |digits: &str, len: usize| {
+            let digits = digits.strip_prefix('#').unwrap_or(digits);
+            u32::from_str_radix(digits, 16)
+                .ok()
+                .filter(|_| digits.len() == len)
+                .ok_or_else(|| syntax(digits, "a hex color"))
+        };
+        match extension {
+            "txt" => {
+                if text.is_empty() || text.starts_with(';') {
+                    continue;
+                }
+                let argb = hex(text, 8)?;
+                colors.push(argb.rotate_left(8));
+            }
+            "hex" => {
+                if text.is_empty() {
+                    continue;
+                }
+                colors.push(hex(text, 6)? << 8 | 0xFF);
+            }
+            "gpl" => {
+                if line == 1 {
+                    if text != "GIMP Palette" {
+                        return Err(syntax(text, "GIMP Palette"));
+                    }
+                    continue;
+                }
+                if text.is_empty()
+                    || text.starts_with('#')
+                    || text.starts_with("Name:")
+                    || text.starts_with("Columns:")
+                {
+                    continue;
+                }
+                // anything after the channels is the color's name
+                let channels = text.split_whitespace().take(3).collect::<Vec<_>>();
+                colors.push(rgb(&channels, |token| syntax(token, "R G B"))?);
+            }
+            "pal" => {
+                let expected = match line {
+                    1 => Some("JASC-PAL"),
+                    2 => Some("0100"),
+                    _ => None,
+                };
+                match expected {
+                    Some(expected) if text != expected => return Err(syntax(text, expected)),
+                    Some(_) => continue,
+                    // the color count, checked once all colors are read
+                    None if line == 3 || text.is_empty() => continue,
+                    None => {
+                        let channels = text.split_whitespace().collect::<Vec<_>>();
+                        colors.push(rgb(&channels, |token| syntax(token, "R G B"))?);
+                    }
+                }
+            }
+            _ => return Err(syntax(extension, "toml, txt, gpl, hex or pal")),
+        }
+    }
+
+    if extension == "pal" {
+        let count = palette_string.lines().nth(2).unwrap_or_default().trim();
+        if count.parse() != Ok(colors.len()) {
+            return Err(PaletteError::Syntax {
+                line: 3,
+                token: count.to_string(),
+                expected: format!("{} colors", colors.len()),
+            });
+        }
+    }
+    Ok(colors)
+}
+
+/// Decimal channels from 0 to 255 as an opaque color
+fn rgb(channels: &[&str], syntax: impl Fn(&str) -> PaletteError) -> Result<u32, PaletteError> {
+    let [r, g, b] = channels else {
+        return Err(syntax(&channels.join(" ")));
+    };
+    let channel = |token: &str| token.parse::<u8>().map_err(|_| syntax(token));
+    Ok(u32::from_be_bytes([
+        channel(r)?,
+        channel(g)?,
+        channel(b)?,
+        0xFF,
+    ]))
+}
+
+impl TryFrom<Vec<u32>> for Palette {
+    type Error = PaletteError;
+
+    /// Fails unless there are between `MIN_PALETTE_SIZE` and `MAX_PALETTE_SIZE` colors
+    fn try_from(colors: Vec<u32>) -> Result<Self, Self::Error> {
+        match colors.len() {
+            count if (MIN_PALETTE_SIZE..=MAX_PALETTE_SIZE).contains(&count) => {
+                Ok(Palette { colors })
+            }
+            count => Err(PaletteError::ColorCount { count }),
+        }
+    }
+}
+
+pub struct PaletteLoader;
+
+impl Loader<Palette> for PaletteLoader {
+    fn load(content: Cow<[u8]>, ext: &str) -> Result<Palette, BoxedError> {
+        let palette_string = std::str::from_utf8(&content).map_err(PaletteError::Encoding)?;
+        Ok(Palette::try_from(parse_colors(palette_string, ext)?)?)
+    }
+}
+
+/// Palettes load from any of the formats of `parse_colors`, so ones
+/// downloaded from Lospec can be used as they are.
+impl Asset for Palette {
+    const EXTENSIONS: &'static [&'static str] = &["toml", "txt", "gpl", "hex", "pal"];
+    type Loader = PaletteLoader;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RGB: [u32; 3] = [0xFF0000FF, 0x00FF00FF, 0x0000FFFF];
+
+    #[test]
+    fn formats() {
+        let txt = ";paint.net Palette File\n;Colors: 3\nFFFF0000\nFF00FF00\nFF0000FF\n";
+        let gpl = "GIMP Palette\nName: rgb\nColumns: 3\n#\n255   0   0 red\n  0 255   0\t\n0 0 255 blue\n";
+        let hex = "ff0000\r\n00ff00\r\n0000ff\r\n";
+        let pal = "JASC-PAL\n0100\n3\n255 0 0\n0 255 0\n0 0 255\n";
+        for (palette, extension) in [(txt, "txt"), (gpl, "gpl"), (hex, "hex"), (pal, "pal")] {
+            assert_eq!(
+                RGB[..],
+                parse_colors(palette, extension).unwrap(),
+                "{extension}"
+            );
+        }
+    }
+
+    #[test]
+    fn jehkoba32_txt_matches_toml() {
+        let txt = parse_colors(include_str!("../assets/jehkoba32.txt"), "txt").unwrap();
+        let toml = parse_colors(include_str!("../assets/palette.toml"), "toml").unwrap();
+        assert_eq!(toml, txt);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(
+            parse_colors("JASC-PAL\n0100\n4\n255 0 0\n", "pal"),
+            Err(PaletteError::Syntax { line: 3, .. })
+        ));
+        let error = parse_colors("ff0000\nf00\n", "hex").unwrap_err();
+        assert_eq!("line 2: `f00`: expected a hex color", error.to_string());
+
+        assert_eq!(3, Palette::try_from(RGB.to_vec()).unwrap().colors.len());
+        assert!(Palette::try_from(vec![0; 256]).is_ok());
+        for count in [1, 257] {
+            assert!(matches!(
+                Palette::try_from(vec![0; count]),
+                Err(PaletteError::ColorCount { count: c }) if c == count
+            ));
+        }
+    }
+}
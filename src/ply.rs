@@ -0,0 +1,730 @@
+use std::{borrow::Cow, collections::HashMap, fmt, fs, path::Path};
+
+use assets_manager::{loader::Loader, AnyCache, Asset, BoxedError};
+use glam::{Vec2, Vec3};
+
+use crate::{
+    color::{Material, Materials, Palette},
+    geo::{self, CullMode, IndexedTriangle, Mesh, RenderMode, Winding},
+    mtl,
+    obj::Location,
+};
+
+#[derive(Debug)]
+pub enum PlyError {
+    Io {
+        file: Option<String>,
+        source: std::io::Error,
+    },
+    Header {
+        location: Location,
+        expected: String,
+    },
+    /// a value of the body that is missing or can't be read
+    Data {
+        file: Option<String>,
+        element: String,
+        row: usize,
+        expected: String,
+    },
+    MissingProperty {
+        file: Option<String>,
+        element: String,
+        property: String,
+    },
+    /// an index that is negative, not a whole number, or refers to no vertex
+    BadIndex {
+        file: Option<String>,
+        face: usize,
+        index: f64,
+        count: usize,
+    },
+}
+
+impl PlyError {
+    /// Adds the file name to the error
+    pub fn in_file(self, name: impl Into<String>) -> Self {
+        let name = Some(name.into());
+        match self {
+            PlyError::Io { source, .. } => PlyError::Io { file: name, source },
+            PlyError::Header { location, expected } => PlyError::Header {
+                location: Location {
+                    file: name,
+                    ..location
+                },
+                expected,
+            },
+            PlyError::Data {
+                element,
+                row,
+                expected,
+                ..
+            } => PlyError::Data {
+                file: name,
+                element,
+                row,
+                expected,
+            },
+            PlyError::MissingProperty {
+                element, property, ..
+            } => PlyError::MissingProperty {
+                file: name,
+                element,
+                property,
+            },
+            PlyError::BadIndex {
+                face, index, count, ..
+            } => PlyError::BadIndex {
+                file: name,
+                face,
+                index,
+                count,
+            },
+        }
+    }
+}
+
+impl fmt::Display for PlyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file = |file: &Option<String>| file.clone().unwrap_or_else(|| "<ply>".to_string());
+        match self {
+            PlyError::Io { file: name, source } => write!(f, "{}: {source}", file(name)),
+            PlyError::Header { location, expected } => {
+                write!(f, "{location}: expected {expected}")
+            }
+            PlyError::Data {
+                file: name,
+                element,
+                row,
+                expected,
+            } => write!(f, "{}: {element} {row}: expected {expected}", file(name)),
+            PlyError::MissingProperty {
+                file: name,
+                element,
+                property,
+            } => write!(f, "{}: {element} has no property {property}", file(name)),
+            PlyError::BadIndex {
+                file: name,
+                face,
+                index,
+                count,
+            } => write!(
+                f,
+                "{}: face {face} refers to vertex {index}, but there are only {count}",
+                file(name)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PlyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PlyError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScalarType {
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Float32,
+    Float64,
+}
+
+impl ScalarType {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "char" | "int8" => ScalarType::Int8,
+            "uchar" | "uint8" => ScalarType::UInt8,
+            "short" | "int16" => ScalarType::Int16,
+            "ushort" | "uint16" => ScalarType::UInt16,
+            "int" | "int32" => ScalarType::Int32,
+            "uint" | "uint32" => ScalarType::UInt32,
+            "float" | "float32" => ScalarType::Float32,
+            "double" | "float64" => ScalarType::Float64,
+            _ => return None,
+        })
+    }
+
+    fn size(self) -> usize {
+        match self {
+            ScalarType::Int8 | ScalarType::UInt8 => 1,
+            ScalarType::Int16 | ScalarType::UInt16 => 2,
+            ScalarType::Int32 | ScalarType::UInt32 | ScalarType::Float32 => 4,
+            ScalarType::Float64 => 8,
+        }
+    }
+
+    /// The value of full intensity for colors of this type
+    fn color_scale(self) -> f32 {
+        match self {
+            ScalarType::Int8 => i8::MAX as f32,
+            ScalarType::UInt8 => u8::MAX as f32,
+            ScalarType::Int16 => i16::MAX as f32,
+            ScalarType::UInt16 => u16::MAX as f32,
+            ScalarType::Int32 => i32::MAX as f32,
+            ScalarType::UInt32 => u32::MAX as f32,
+            ScalarType::Float32 | ScalarType::Float64 => 1.,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Property {
+    Scalar(ScalarType),
+    /// a count of type `.0` followed by that many items of type `.1`
+    List(ScalarType, ScalarType),
+}
+
+#[derive(Debug, Clone)]
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<(String, Property)>,
+}
+
+impl Element {
+    fn property(&self, name: &str) -> Option<(usize, ScalarType)> {
+        self.properties
+            .iter()
+            .enumerate()
+            .find_map(|(i, (property, ty))| match ty {
+                Property::Scalar(ty) if property == name => Some((i, *ty)),
+                _ => None,
+            })
+    }
+
+    /// The first of `names` that is a scalar property
+    fn any_property(&self, names: &[&str]) -> Option<(usize, ScalarType)> {
+        names.iter().find_map(|name| self.property(name))
+    }
+}
+
+/// Reads the values of the body one at a time
+enum Body<'a> {
+    Ascii(std::str::SplitAsciiWhitespace<'a>),
+    Binary { bytes: &'a [u8], big_endian: bool },
+}
+
+impl<'a> Body<'a> {
+    fn read(&mut self, ty: ScalarType) -> Option<f64> {
+        match self {
+            Body::Ascii(tokens) => tokens.next()?.parse().ok(),
+            Body::Binary { bytes, big_endian } => {
+                let all: &'a [u8] = bytes;
+                let (value, rest) = all.split_at_checked(ty.size())?;
+                *bytes = rest;
+                let mut le = [0; 8];
+                le[..value.len()].copy_from_slice(value);
+                if *big_endian {
+                    le[..value.len()].reverse();
+                }
+                let [b0, b1, b2, b3, ..] = le;
+                Some(match ty {
+                    ScalarType::Int8 => b0 as i8 as f64,
+                    ScalarType::UInt8 => b0 as f64,
+                    ScalarType::Int16 => i16::from_le_bytes([b0, b1]) as f64,
+                    ScalarType::UInt16 => u16::from_le_bytes([b0, b1]) as f64,
+                    ScalarType::Int32 => i32::from_le_bytes([b0, b1, b2, b3]) as f64,
+                    ScalarType::UInt32 => u32::from_le_bytes([b0, b1, b2, b3]) as f64,
+                    ScalarType::Float32 => f32::from_le_bytes([b0, b1, b2, b3]) as f64,
+                    ScalarType::Float64 => f64::from_le_bytes(le),
+                })
+            }
+        }
+    }
+
+    /// One row of `element`, with scalar properties as lists of one value
+    fn read_row(&mut self, element: &Element) -> Option<Vec<Vec<f64>>> {
+        element
+            .properties
+            .iter()
+            .map(|(_, property)| match *property {
+                Property::Scalar(ty) => Some(vec![self.read(ty)?]),
+                Property::List(count, item) => {
+                    let count = self.read(count)? as usize;
+                    (0..count).map(|_| self.read(item)).collect()
+                }
+            })
+            .collect()
+    }
+}
+
+/// The vertices and faces of a .ply file
+#[derive(Debug, Clone, Default)]
+pub struct PlyData {
+    pub vertices: Vec<Vec3>,
+    pub normals: Option<Vec<Vec3>>,
+    pub uvs: Option<Vec<Vec2>>,
+    /// vertex colors, with channels from 0 to 1
+    pub colors: Option<Vec<Vec3>>,
+    /// polygons, as indices into `vertices`
+    pub faces: Vec<Vec<usize>>,
+}
+
+impl PlyData {
+    /// Faces use the first of `materials`. With a `vertex_colors` palette,
+    /// each triangle instead gets a shade ramp of the average color of its
+    /// vertices, matched to the nearest palette colors like .mtl diffuse colors.
+    pub fn into_mesh(self, materials: Materials, vertex_colors: Option<&Palette>) -> Mesh {
+        let triangles: Vec<(usize, usize, usize)> = self
+            .faces
+            .iter()
+            .flat_map(|face| face[1..].windows(2).map(|pair| (face[0], pair[0], pair[1])))
+            .collect();
+        let normals = match self.normals {
+            Some(normals) => normals.into_iter().map(Vec3::normalize_or_zero).collect(),
+            None => geo::vertex_normals(&self.vertices, &triangles),
+        };
+
+        let (materials, material_indices) = match (vertex_colors, &self.colors) {
+            (Some(palette), Some(colors)) => {
+                let mut ramps: HashMap<Vec<u8>, usize> = HashMap::new();
+                let mut color_materials = Vec::new();
+                let material_indices = triangles
+                    .iter()
+                    .map(|&(a, b, c)| {
+                        let color = (colors[a] + colors[b] + colors[c]) / 3.;
+                        let shades = mtl::shade_ramp(color, palette);
+                        *ramps.entry(shades.clone()).or_insert_with(|| {
+                            color_materials.push(Material {
+                                shades,
+                                curve: None,
+                                shader: None,
+                            });
+                            color_materials.len() - 1
+                        })
+                    })
+                    .collect();
+                let names = (0..color_materials.len())
+                    .map(|i| format!("color{i}"))
+                    .collect();
+                (Materials::new(color_materials, names), material_indices)
+            }
+            (vertex_colors, _) => {
+                if vertex_colors.is_some() {
+                    log::warn!("PLY: No vertex colors, using the default material");
+                }
+                (materials, vec![0; triangles.len()])
+            }
+        };
+
+        Mesh {
+            triangles: triangles
+                .into_iter()
+                .zip(material_indices)
+                .map(|(index, material_index)| IndexedTriangle {
+                    index,
+                    normal_index: index,
+                    uv_index: self.uvs.is_some().then_some(index),
+                    material_index,
+                })
+                .collect(),
+            vertices: self.vertices,
+            normals,
+            uvs: self.uvs.unwrap_or_default(),
+            lines: Vec::new(),
+            materials,
+            texture: None,
+            cull_mode: CullMode::default(),
+            winding: Winding::default(),
+            render_mode: RenderMode::default(),
+        }
+    }
+}
+
+/// Reads a .ply file, see `PlyData::into_mesh`
+pub fn parse(
+    path: &Path,
+    materials: Materials,
+    vertex_colors: Option<&Palette>,
+) -> Result<Mesh, PlyError> {
+    let file = path.display().to_string();
+    let bytes = fs::read(path).map_err(|source| PlyError::Io {
+        file: Some(file.clone()),
+        source,
+    })?;
+    let data = parse_bytes(&bytes).map_err(|e| e.in_file(file))?;
+    Ok(data.into_mesh(materials, vertex_colors))
+}
+
+/// Parses ASCII or binary PLY. Vertices need `x`, `y` and `z`, and may have
+/// normals, texture coordinates and colors. Elements other than `vertex` and
+/// `face` are skipped.
+pub fn parse_bytes(bytes: &[u8]) -> Result<PlyData, PlyError> {
+    let (format, elements, body) = parse_header(bytes)?;
+    let mut body = match format {
+        Format::Ascii => Body::Ascii(
+            std::str::from_utf8(body)
+                .map_err(|_| PlyError::Data {
+                    file: None,
+                    element: elements
+                        .first()
+                        .map_or_else(String::new, |e| e.name.clone()),
+                    row: 0,
+                    expected: "ASCII values".to_string(),
+                })?
+                .split_ascii_whitespace(),
+        ),
+        Format::BinaryLittleEndian | Format::BinaryBigEndian => Body::Binary {
+            bytes: body,
+            big_endian: format == Format::BinaryBigEndian,
+        },
+    };
+
+    let mut data = PlyData::default();
+    // as read, so that negative and fractional indices can be reported
+    let mut faces: Vec<Vec<f64>> = Vec::new();
+    for element in &elements {
+        let rows = (0..element.count)
+            .map(|row| {
+                body.read_row(element).ok_or_else(|| PlyError::Data {
+                    file: None,
+                    element: element.name.clone(),
+                    row,
+                    expected: format!("{} properties", element.properties.len()),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        match element.name.as_str() {
+            "vertex" => read_vertices(element, &rows, &mut data)?,
+            "face" => {
+                let (indices, _) = ["vertex_indices", "vertex_index"]
+                    .iter()
+                    .find_map(|name| {
+                        element
+                            .properties
+                            .iter()
+                            .enumerate()
+                            .find(|(_, (property, ty))| {
+                                property == name && matches!(ty, Property::List(..))
+                            })
+                    })
+                    .ok_or_else(|| PlyError::MissingProperty {
+                        file: None,
+                        element: element.name.clone(),
+                        property: "vertex_indices".to_string(),
+                    })?;
+                faces = rows
+                    .into_iter()
+                    .map(|mut row| row.swap_remove(indices))
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+
+    let count = data.vertices.len();
+    let valid = |i: f64| i >= 0. && i.fract() == 0. && i < count as f64;
+    for (face, indices) in faces.into_iter().enumerate() {
+        if let Some(&index) = indices.iter().find(|&&i| !valid(i)) {
+            return Err(PlyError::BadIndex {
+                file: None,
+                face,
+                index,
+                count,
+            });
+        }
+        if indices.len() < 3 {
+            return Err(PlyError::Data {
+                file: None,
+                element: "face".to_string(),
+                row: face,
+                expected: "at least 3 vertices".to_string(),
+            });
+        }
+        data.faces
+            .push(indices.into_iter().map(|i| i as usize).collect());
+    }
+    Ok(data)
+}
+
+/// The format, the elements and the rest of the file after `end_header`
+fn parse_header(bytes: &[u8]) -> Result<(Format, Vec<Element>, &[u8]), PlyError> {
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    let mut rest = bytes;
+    let mut line = 0;
+    loop {
+        let end = rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len());
+        let text = String::from_utf8_lossy(&rest[..end]);
+        rest = rest.get(end + 1..).unwrap_or_default();
+        line += 1;
+
+        let header = |token: &str, expected: &str| PlyError::Header {
+            location: Location {
+                file: None,
+                line,
+                token: token.to_string(),
+            },
+            expected: expected.to_string(),
+        };
+        let mut tokens = text.split_whitespace();
+        match (line, tokens.next()) {
+            (1, Some("ply")) => {}
+            (1, _) => return Err(header(text.trim(), "ply")),
+            (_, Some("format")) => {
+                format = Some(match tokens.next() {
+                    Some("ascii") => Format::Ascii,
+                    Some("binary_little_endian") => Format::BinaryLittleEndian,
+                    Some("binary_big_endian") => Format::BinaryBigEndian,
+                    token => {
+                        return Err(header(
+                            token.unwrap_or_default(),
+                            "ascii, binary_little_endian or binary_big_endian",
+                        ))
+                    }
+                });
+            }
+            (_, Some("element")) => {
+                let name = tokens.next().ok_or_else(|| header(text.trim(), "a name"))?;
+                let count = tokens.next().unwrap_or_default();
+                elements.push(Element {
+                    name: name.to_string(),
+                    count: count.parse().map_err(|_| header(count, "a count"))?,
+                    properties: Vec::new(),
+                });
+            }
+            (_, Some("property")) => {
+                let element = elements
+                    .last_mut()
+                    .ok_or_else(|| header(text.trim(), "element before property"))?;
+                let scalar = |token: Option<&str>| {
+                    let token = token.unwrap_or_default();
+                    ScalarType::parse(token).ok_or_else(|| header(token, "a property type"))
+                };
+                let property = match tokens.next() {
+                    Some("list") => Property::List(scalar(tokens.next())?, scalar(tokens.next())?),
+                    ty => Property::Scalar(scalar(ty)?),
+                };
+                let name = tokens
+                    .next()
+                    .ok_or_else(|| header(text.trim(), "a property name"))?;
+                element.properties.push((name.to_string(), property));
+            }
+            (_, Some("end_header")) => {
+                let format =
+                    format.ok_or_else(|| header(text.trim(), "format before end_header"))?;
+                return Ok((format, elements, rest));
+            }
+            (_, Some("comment" | "obj_info") | None) => {}
+            (_, Some(token)) => {
+                if rest.is_empty() {
+                    return Err(header(token, "end_header"));
+                }
+                return Err(header(token, "format, element, property or end_header"));
+            }
+        }
+        if rest.is_empty() {
+            return Err(header("", "end_header"));
+        }
+    }
+}
+
+fn read_vertices(
+    element: &Element,
+    rows: &[Vec<Vec<f64>>],
+    data: &mut PlyData,
+) -> Result<(), PlyError> {
+    let require = |name: &str| {
+        element
+            .property(name)
+            .ok_or_else(|| PlyError::MissingProperty {
+                file: None,
+                element: element.name.clone(),
+                property: name.to_string(),
+            })
+    };
+    let position = [require("x")?, require("y")?, require("z")?];
+    let normal = [
+        element.property("nx"),
+        element.property("ny"),
+        element.property("nz"),
+    ];
+    let uv = [
+        element.any_property(&["u", "s", "texture_u"]),
+        element.any_property(&["v", "t", "texture_v"]),
+    ];
+    let color = [
+        element.any_property(&["red", "diffuse_red"]),
+        element.any_property(&["green", "diffuse_green"]),
+        element.any_property(&["blue", "diffuse_blue"]),
+    ];
+
+    let value = |row: &[Vec<f64>], (i, _): (usize, ScalarType)| row[i][0] as f32;
+    data.vertices = rows
+        .iter()
+        .map(|row| Vec3::from_array(position.map(|p| value(row, p))))
+        .collect();
+    if let [Some(x), Some(y), Some(z)] = normal {
+        data.normals = Some(
+            rows.iter()
+                .map(|row| Vec3::new(value(row, x), value(row, y), value(row, z)))
+                .collect(),
+        );
+    }
+    if let [Some(u), Some(v)] = uv {
+        data.uvs = Some(
+            rows.iter()
+                .map(|row| Vec2::new(value(row, u), value(row, v)))
+                .collect(),
+        );
+    }
+    if let [Some(r), Some(g), Some(b)] = color {
+        let channel = |row: &[Vec<f64>], c: (usize, ScalarType)| value(row, c) / c.1.color_scale();
+        data.colors = Some(
+            rows.iter()
+                .map(|row| Vec3::new(channel(row, r), channel(row, g), channel(row, b)))
+                .collect(),
+        );
+    }
+    Ok(())
+}
+
+pub struct PlyLoader;
+
+impl Loader<PlyData> for PlyLoader {
+    fn load(content: Cow<[u8]>, _ext: &str) -> Result<PlyData, BoxedError> {
+        Ok(parse_bytes(&content)?)
+    }
+}
+
+impl Asset for PlyData {
+    const EXTENSION: &'static str = "ply";
+    type Loader = PlyLoader;
+}
+
+/// Loads the .ply file `id` from the cache. Its faces use the first material
+/// in the toml file `materials_id`, or a gray ramp of the palette `palette_id`.
+/// With `vertex_colors`, materials come from the vertex colors instead.
+pub fn load_mesh(
+    cache: AnyCache,
+    id: &str,
+    materials_id: Option<&str>,
+    palette_id: &str,
+    vertex_colors: bool,
+) -> Result<Mesh, BoxedError> {
+    let data = cache.load::<PlyData>(id)?.cloned();
+    let materials = mtl::load_materials(cache, materials_id, palette_id)?;
+    let palette = cache.load::<Palette>(palette_id)?.read();
+    Ok(data.into_mesh(materials, vertex_colors.then_some(&*palette)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUAD: &str = "ply
+format ascii 1.0
+comment a red and blue square
+element vertex 4
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+property uchar blue
+element face 1
+property list uchar int vertex_indices
+end_header
+0 0 0 255 0 0
+1 0 0 255 0 0
+1 1 0 0 0 255
+0 1 0 0 0 255
+4 0 1 2 3
+";
+
+    fn rgb_palette() -> Palette {
+        let mut colors = [0xFF; 32];
+        colors[1] = 0xFF0000FF;
+        colors[2] = 0x0000FFFF;
+        Palette {
+            colors: colors.to_vec(),
+        }
+    }
+
+    #[test]
+    fn parse_ascii() {
+        let data = parse_bytes(QUAD.as_bytes()).unwrap();
+        assert_eq!(4, data.vertices.len());
+        assert_eq!(Vec3::X, data.colors.as_ref().unwrap()[0]);
+        assert_eq!(vec![vec![0, 1, 2, 3]], data.faces);
+
+        let palette = rgb_palette();
+        let mesh = data.clone().into_mesh(Materials::default(), Some(&palette));
+        assert_eq!(2, mesh.triangles.len());
+        assert_eq!(vec!["color0", "color1"], mesh.materials.names);
+        assert_eq!(Vec3::Z, mesh.normals[0]);
+        // the first triangle has two red vertices, the second two blue ones
+        assert_eq!(1, mesh.materials.materials[0].shades[8]);
+        assert_eq!(2, mesh.materials.materials[1].shades[8]);
+
+        let mesh = data.into_mesh(Materials::default(), None);
+        assert!(mesh.triangles.iter().all(|t| t.material_index == 0));
+    }
+
+    #[test]
+    fn parse_binary() {
+        let ascii = parse_bytes(QUAD.as_bytes()).unwrap();
+        let header = QUAD
+            .split_inclusive("end_header\n")
+            .next()
+            .unwrap()
+            .replace("ascii", "binary_big_endian");
+        let mut bytes = header.into_bytes();
+        for (vertex, color) in ascii.vertices.iter().zip(ascii.colors.unwrap()) {
+            bytes.extend(vertex.to_array().map(f32::to_be_bytes).concat());
+            bytes.extend((color * 255.).to_array().map(|c| c as u8));
+        }
+        bytes.push(4);
+        bytes.extend([0i32, 1, 2, 3].map(i32::to_be_bytes).concat());
+
+        let binary = parse_bytes(&bytes).unwrap();
+        assert_eq!(ascii.vertices, binary.vertices);
+        assert_eq!(ascii.faces, binary.faces);
+
+        bytes.pop();
+        assert!(matches!(
+            parse_bytes(&bytes),
+            Err(PlyError::Data { row: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn errors() {
+        let bad_index = QUAD.replace("4 0 1 2 3", "3 0 1 4");
+        assert!(matches!(
+            parse_bytes(bad_index.as_bytes()),
+            Err(PlyError::BadIndex { index: 4., .. })
+        ));
+        // negative and fractional indices refer to no vertex either
+        for index in ["-1", "1.5"] {
+            let bad_index = QUAD.replace("4 0 1 2 3", &format!("3 0 {index} 2"));
+            assert!(matches!(
+                parse_bytes(bad_index.as_bytes()),
+                Err(PlyError::BadIndex { face: 0, .. })
+            ));
+        }
+        let error = parse_bytes(QUAD.replace("uchar blue", "rgb blue").as_bytes())
+            .unwrap_err()
+            .in_file("quad.ply");
+        assert_eq!(
+            "quad.ply:10: `rgb`: expected a property type",
+            error.to_string()
+        );
+    }
+}
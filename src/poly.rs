@@ -1,44 +1,104 @@
-use std::cmp::Ordering;
+use std::{
+    cmp::Ordering,
+    ops::{Add, Mul},
+};
 
 use crate::{
-    buffer::Buffer,
-    color::{lit_color, Material},
-    interpolate::lerp,
+    buffer::{Buffer, SpanEnd, TriangleId},
+    interpolate::{interpolate, lerp, Barycentric},
 };
-use glam::{f32::Vec3, Vec3Swizzles};
+use glam::{f32::Vec3, I64Vec2, Vec2, Vec3Swizzles};
+
+/// Selects how `draw_tri` turns triangles into fragments
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Rasterizer {
+    /// Splits triangles into flat topped and flat bottomed halves and fills scanlines
+    #[default]
+    Scanline,
+    /// Tests every pixel in the bounding box against the three edges,
+    /// with a top-left fill rule so shared edges are covered exactly once
+    EdgeFunction,
+}
+
+/// Per-vertex values that are interpolated across a triangle for each fragment
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Varyings {
+    pub light: f32,
+    pub uv: Vec2,
+    /// world space normal
+    pub normal: Vec3,
+    /// world space position
+    pub position: Vec3,
+}
+
+impl Add for Varyings {
+    type Output = Varyings;
+    fn add(self, rhs: Self) -> Self::Output {
+        Varyings {
+            light: self.light + rhs.light,
+            uv: self.uv + rhs.uv,
+            normal: self.normal + rhs.normal,
+            position: self.position + rhs.position,
+        }
+    }
+}
 
+impl Mul<f32> for Varyings {
+    type Output = Varyings;
+    fn mul(self, rhs: f32) -> Self::Output {
+        Varyings {
+            light: self.light * rhs,
+            uv: self.uv * rhs,
+            normal: self.normal * rhs,
+            position: self.position * rhs,
+        }
+    }
+}
+
+/// A triangle in screen space, with depth in z
 pub struct Tri {
     pub v1: Vec3,
     pub v2: Vec3,
     pub v3: Vec3,
-    pub base_color: Material,
-    pub illumination: f32,
-    idx: usize,
+    /// values at `v1`, `v2` and `v3`
+    pub varyings: [Varyings; 3],
+    barycentric: Barycentric,
+    pub(crate) id: TriangleId,
 }
 
 impl Tri {
-    pub fn new(v1: Vec3, v2: Vec3, v3: Vec3, base_color: Material, idx: usize) -> Tri {
-        let diffuse_light = 0.08;
-        let light_pos = Vec3::new(1000.0, -1000.0, 500.0);
-        let tri_pos = (v1 + v2 + v3) / 3.;
-        let cross_1 = v1 - v2;
-        let cross_2 = v3 - v1;
-        let normal = cross_1.cross(cross_2).normalize();
-        let light_to_tri = (tri_pos - light_pos).normalize();
-        let illumination = (normal.dot(light_to_tri).clamp(0.0, 1.0)) + diffuse_light;
-
+    /// `inv_w` is `1 / w` of each vertex in clip space, for perspective correction
+    pub fn new(vertices: [Vec3; 3], inv_w: Vec3, varyings: [Varyings; 3], id: TriangleId) -> Tri {
+        let [v1, v2, v3] = vertices;
         Tri {
             v1,
             v2,
             v3,
-            base_color,
-            illumination,
-            idx,
+            varyings,
+            barycentric: Barycentric::new(v1.xy(), v2.xy(), v3.xy(), inv_w),
+            id,
         }
     }
+
+    /// Screen space barycentric weights of the point `p`
+    pub fn weights(&self, p: Vec2) -> Vec3 {
+        self.barycentric.weights(p)
+    }
+
+    /// Perspective correct varyings at the point with screen space `weights`
+    pub fn varyings(&self, weights: Vec3) -> Varyings {
+        interpolate(self.varyings, self.barycentric.perspective(weights))
+    }
 }
 
 pub fn draw_tri(buffer: &mut Buffer, tri: &Tri) {
+    match buffer.rasterizer {
+        Rasterizer::Scanline => draw_tri_scanline(buffer, tri),
+        Rasterizer::EdgeFunction => draw_tri_edge_function(buffer, tri),
+    }
+}
+
+fn draw_tri_scanline(buffer: &mut Buffer, tri: &Tri) {
     let (up_tri, down_tri) = split_tri(tri);
     if let Some(up_tri) = up_tri {
         up_tri.draw_up(buffer);
@@ -53,24 +113,16 @@ pub fn draw_tri(buffer: &mut Buffer, tri: &Tri) {
 }
 
 #[derive(Copy, Clone)]
-struct UpDownTri {
+struct UpDownTri<'a> {
     tip: Vec3,
     base_left: Vec3,
     base_right: Vec3,
-    base_color: Material,
-    illumination: f32,
-    idx: usize,
-}
-
-impl UpDownTri {
-    fn new(
-        base_1: Vec3,
-        base_2: Vec3,
-        tip: Vec3,
-        base_color: Material,
-        illumination: f32,
-        idx: usize,
-    ) -> UpDownTri {
+    /// the triangle this half was split from
+    tri: &'a Tri,
+}
+
+impl UpDownTri<'_> {
+    fn new(base_1: Vec3, base_2: Vec3, tip: Vec3, tri: &Tri) -> UpDownTri<'_> {
         // TODO WHY?? lifetimes
         assert_eq!(base_1.y, base_2.y);
         let (base_left, base_right) = match base_1.x.partial_cmp(&base_2.x) {
@@ -82,59 +134,38 @@ impl UpDownTri {
             tip,
             base_left,
             base_right,
-            base_color,
-            illumination,
-            idx,
+            tri,
         }
     }
 
     fn draw_up(self, buffer: &mut Buffer) {
         let base_y = self.base_left.y.floor() as i32;
         let tip_y = self.tip.y.ceil() as i32;
-        let base_left = self.base_left;
-        let base_right = self.base_right;
-        let tip = self.tip;
 
-        (tip_y..=base_y).for_each(|y| {
-            let x_next_left = lerp(base_left.xy(), tip.xy(), y as f32);
-            let x_next_right = lerp(base_right.xy(), tip.xy(), y as f32);
-            let z_next_left = lerp(base_left.zy(), tip.zy(), y as f32);
-            let z_next_right = lerp(base_right.zy(), tip.zy(), y as f32);
-
-            buffer.h_line(
-                x_next_left,
-                x_next_right,
-                y,
-                z_next_left,
-                z_next_right,
-                self.idx,
-                self.illumination,
-            )
-        });
+        (tip_y..=base_y).for_each(|y| self.draw_span(buffer, y));
     }
     fn draw_down(self, buffer: &mut Buffer) {
         let base_y = self.base_left.y.ceil() as i32;
         let tip_y = self.tip.y.floor() as i32;
+
+        (base_y..=tip_y).for_each(|y| self.draw_span(buffer, y));
+    }
+
+    fn draw_span(&self, buffer: &mut Buffer, y: i32) {
         let base_left = self.base_left;
         let base_right = self.base_right;
         let tip = self.tip;
+        let y_f = y as f32;
 
-        (base_y..=tip_y).for_each(|y| {
-            let x_next_left = lerp(base_left.xy(), tip.xy(), y as f32);
-            let x_next_right = lerp(base_right.xy(), tip.xy(), y as f32);
-            let z_next_left = lerp(base_left.zy(), tip.zy(), y as f32);
-            let z_next_right = lerp(base_right.zy(), tip.zy(), y as f32);
-
-            buffer.h_line(
-                x_next_left,
-                x_next_right,
-                y,
-                z_next_left,
-                z_next_right,
-                self.idx,
-                self.illumination,
-            )
-        });
+        let left = SpanEnd {
+            x: lerp(base_left.xy(), tip.xy(), y_f),
+            z: lerp(base_left.zy(), tip.zy(), y_f),
+        };
+        let right = SpanEnd {
+            x: lerp(base_right.xy(), tip.xy(), y_f),
+            z: lerp(base_right.zy(), tip.zy(), y_f),
+        };
+        buffer.h_line(left, right, y, self.tri)
     }
 }
 
@@ -145,7 +176,7 @@ impl UpDownTri {
 //
 //impl SplitTriangle<'_> {
 //*/
-fn split_tri<'a>(tri: &'a Tri) -> (Option<UpDownTri>, Option<UpDownTri>) {
+fn split_tri(tri: &Tri) -> (Option<UpDownTri<'_>>, Option<UpDownTri<'_>>) {
     let mut points = [tri.v1, tri.v2, tri.v3];
     points.sort_by(|t1, t2| t1.y.partial_cmp(&t2.y).unwrap());
     let top_point = points[0];
@@ -160,14 +191,7 @@ fn split_tri<'a>(tri: &'a Tri) -> (Option<UpDownTri>, Option<UpDownTri>) {
     // check if already up
     if bot_point.y == mid_point.y {
         return (
-            Some(UpDownTri::new(
-                bot_point,
-                mid_point,
-                top_point,
-                tri.base_color,
-                tri.illumination,
-                tri.idx,
-            )),
+            Some(UpDownTri::new(bot_point, mid_point, top_point, tri)),
             None,
         );
     };
@@ -175,36 +199,168 @@ fn split_tri<'a>(tri: &'a Tri) -> (Option<UpDownTri>, Option<UpDownTri>) {
     if top_point.y == mid_point.y {
         return (
             None,
-            Some(UpDownTri::new(
-                top_point,
-                mid_point,
-                bot_point,
-                tri.base_color,
-                tri.illumination,
-                tri.idx,
-            )),
+            Some(UpDownTri::new(top_point, mid_point, bot_point, tri)),
         );
     };
 
     let new_base_y = mid_point.y;
     let new_base_x = lerp(top_point.xy(), bot_point.xy(), new_base_y);
     let new_base_z = lerp(top_point.zy(), bot_point.zy(), new_base_y);
+    let new_base = Vec3::new(new_base_x, new_base_y, new_base_z);
 
-    let up_tri = Some(UpDownTri::new(
-        mid_point,
-        Vec3::new(new_base_x, new_base_y, new_base_z),
-        top_point,
-        tri.base_color,
-        tri.illumination,
-        tri.idx,
-    ));
-    let down_tri = Some(UpDownTri::new(
-        mid_point,
-        Vec3::new(new_base_x, new_base_y, new_base_z),
-        bot_point,
-        tri.base_color,
-        tri.illumination,
-        tri.idx,
-    ));
+    let up_tri = Some(UpDownTri::new(mid_point, new_base, top_point, tri));
+    let down_tri = Some(UpDownTri::new(mid_point, new_base, bot_point, tri));
     (up_tri, down_tri)
 }
+
+/// Number of fractional bits used for vertex positions by the edge function rasterizer
+const SUBPIXEL_BITS: u32 = 4;
+const SUBPIXEL_ONE: i64 = 1 << SUBPIXEL_BITS;
+
+fn draw_tri_edge_function(buffer: &mut Buffer, tri: &Tri) {
+    let (width, height) = (buffer.width(), buffer.height());
+    edge_function_coverage(tri.v1, tri.v2, tri.v3, width, height, |x, y, weights| {
+        let z = weights.dot(Vec3::new(tri.v1.z, tri.v2.z, tri.v3.z));
+        buffer.fragment(x, y, z, tri.id, tri.varyings(weights));
+    });
+}
+
+fn to_fixed(v: Vec3) -> I64Vec2 {
+    (v.xy() * SUBPIXEL_ONE as f32).round().as_i64vec2()
+}
+
+/// Twice the signed area of `a`, `b`, `p`. Positive when `p` is to the right of
+/// `a` -> `b` on screen (+y is down).
+fn orient(a: I64Vec2, b: I64Vec2, p: I64Vec2) -> i64 {
+    (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x)
+}
+
+/// With clockwise on screen winding, top edges point right and left edges point up
+fn is_top_left(a: I64Vec2, b: I64Vec2) -> bool {
+    let edge = b - a;
+    (edge.y == 0 && edge.x > 0) || edge.y < 0
+}
+
+/// Calls `f` with the barycentric weights of every pixel covered by the triangle.
+///
+/// Pixels are sampled at integer coordinates, like the scanline rasterizer.
+/// Positions are snapped to a `1 / SUBPIXEL_ONE` grid, and pixels exactly on an
+/// edge only belong to the triangle if it is a top or left edge.
+pub fn edge_function_coverage<F>(
+    v1: Vec3,
+    v2: Vec3,
+    v3: Vec3,
+    width: usize,
+    height: usize,
+    mut f: F,
+) where
+    F: FnMut(usize, usize, Vec3),
+{
+    let (p1, mut p2, mut p3) = (to_fixed(v1), to_fixed(v2), to_fixed(v3));
+    let mut area = orient(p1, p2, p3);
+    // the weights are permuted back at the end, so winding doesn't matter here
+    let swapped = area < 0;
+    if swapped {
+        (p2, p3) = (p3, p2);
+        area = -area;
+    }
+    if area == 0 || width == 0 || height == 0 {
+        return;
+    }
+
+    let min = p1.min(p2).min(p3);
+    let max = p1.max(p2).max(p3);
+    // pixel sample points covering the bounding box, clamped to the screen
+    let x_start = (min.x + SUBPIXEL_ONE - 1).div_euclid(SUBPIXEL_ONE).max(0);
+    let y_start = (min.y + SUBPIXEL_ONE - 1).div_euclid(SUBPIXEL_ONE).max(0);
+    let x_end = max.x.div_euclid(SUBPIXEL_ONE).min(width as i64 - 1);
+    let y_end = max.y.div_euclid(SUBPIXEL_ONE).min(height as i64 - 1);
+    if x_start > x_end || y_start > y_end {
+        return;
+    }
+
+    // each edge is opposite the vertex whose weight it gives
+    let edges = [(p2, p3), (p3, p1), (p1, p2)];
+    let bias = edges.map(|(a, b)| if is_top_left(a, b) { 0 } else { -1 });
+    let step_x = edges.map(|(a, b)| -(b.y - a.y) * SUBPIXEL_ONE);
+    let step_y = edges.map(|(a, b)| (b.x - a.x) * SUBPIXEL_ONE);
+
+    let origin = I64Vec2::new(x_start, y_start) * SUBPIXEL_ONE;
+    let mut row = edges.map(|(a, b)| orient(a, b, origin));
+
+    for y in y_start..=y_end {
+        let mut w = row;
+        for x in x_start..=x_end {
+            if (0..3).all(|i| w[i] + bias[i] >= 0) {
+                let weights = Vec3::new(w[0] as f32, w[1] as f32, w[2] as f32) / area as f32;
+                let weights = if swapped { weights.xzy() } else { weights };
+                f(x as usize, y as usize, weights);
+            }
+            (0..3).for_each(|i| w[i] += step_x[i]);
+        }
+        (0..3).for_each(|i| row[i] += step_y[i]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn coverage(tris: &[[Vec3; 3]]) -> HashMap<(usize, usize), usize> {
+        let mut counts = HashMap::new();
+        for [v1, v2, v3] in tris {
+            edge_function_coverage(*v1, *v2, *v3, 32, 32, |x, y, _| {
+                *counts.entry((x, y)).or_insert(0) += 1;
+            });
+        }
+        counts
+    }
+
+    #[test]
+    fn shared_edge_is_watertight() {
+        // a 4x4 pixel square split along its diagonal
+        let a = Vec3::new(2., 2., 0.);
+        let b = Vec3::new(6., 2., 0.);
+        let c = Vec3::new(6., 6., 0.);
+        let d = Vec3::new(2., 6., 0.);
+        let counts = coverage(&[[a, b, c], [a, c, d]]);
+        // top-left rule: rows and columns 2..6
+        assert_eq!(16, counts.len());
+        assert!(counts.values().all(|&n| n == 1));
+        assert!(counts.contains_key(&(2, 2)));
+        assert!(!counts.contains_key(&(6, 6)));
+    }
+
+    #[test]
+    fn fan_is_watertight() {
+        // several triangles around an off-grid center
+        let center = Vec3::new(10.3, 9.7, 0.);
+        let ring = [
+            Vec3::new(3.1, 2.6, 0.),
+            Vec3::new(17.9, 4.2, 0.),
+            Vec3::new(19.4, 16.8, 0.),
+            Vec3::new(8.5, 18.1, 0.),
+            Vec3::new(1.2, 11.9, 0.),
+        ];
+        let tris: Vec<_> = (0..ring.len())
+            .map(|i| [center, ring[i], ring[(i + 1) % ring.len()]])
+            .collect();
+        assert!(coverage(&tris).values().all(|&n| n == 1));
+    }
+
+    #[test]
+    fn weights_interpolate_vertices() {
+        let (v1, v2, v3) = (
+            Vec3::new(0., 0., 0.),
+            Vec3::new(8., 0., 0.),
+            Vec3::new(0., 8., 0.),
+        );
+        edge_function_coverage(v1, v2, v3, 32, 32, |x, y, weights| {
+            let p = weights.x * v1 + weights.y * v2 + weights.z * v3;
+            assert!((p.x - x as f32).abs() < 1e-4);
+            assert!((p.y - y as f32).abs() < 1e-4);
+        });
+    }
+}
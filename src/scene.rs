@@ -0,0 +1,149 @@
+use assets_manager::Asset;
+use glam::{Affine3A, EulerRot, Quat, Vec3};
+use serde::Deserialize;
+
+use crate::{
+    camera::Camera,
+    light::{self, Light},
+};
+
+/// Everything that is on screen, loaded from a toml file like `assets/scene.toml`.
+/// Assets are referred to by their id in the `AssetCache`.
+#[derive(Deserialize, Debug, Clone, Asset)]
+#[asset_format = "toml"]
+pub struct SceneDescription {
+    /// id of a `Palette`
+    pub palette: String,
+    #[serde(default)]
+    pub camera: Camera,
+    #[serde(default = "light::default_lights")]
+    pub lights: Vec<Light>,
+    /// children of the scene's root node
+    #[serde(default)]
+    pub nodes: Vec<NodeDescription>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct NodeDescription {
+    pub name: String,
+    /// id of a mesh file, in `format`
+    #[serde(default)]
+    pub mesh: Option<String>,
+    #[serde(default)]
+    pub format: MeshFormat,
+    /// an `o` object or `g` group in `mesh` to show on its own
+    #[serde(default)]
+    pub object: Option<String>,
+    /// id of a .glb or .gltf file whose scene is added to this node's children
+    #[serde(default)]
+    pub gltf: Option<String>,
+    /// id of the `NamedMaterials` used by `mesh`, instead of the ones next
+    /// to the mesh's `mtllib`. For `gltf`, materials are matched by name.
+    #[serde(default)]
+    pub materials: Option<String>,
+    /// turn the vertex colors of a .ply `mesh` into materials, instead of
+    /// using `materials`
+    #[serde(default)]
+    pub vertex_colors: bool,
+    /// id of a `Texture` for `mesh`, sampled by triangles with texture coordinates
+    #[serde(default)]
+    pub texture: Option<String>,
+    #[serde(default)]
+    pub transform: Transform,
+    #[serde(default)]
+    pub children: Vec<NodeDescription>,
+}
+
+/// File format of a node's `mesh`. Only .obj files name their materials,
+/// .stl and .ply faces all use the first of the node's `materials`, or a gray
+/// default material.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MeshFormat {
+    #[default]
+    Obj,
+    Stl,
+    Ply,
+}
+
+/// A node's transform relative to its parent, applied as scale, then rotation,
+/// then translation
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct Transform {
+    pub translation: Vec3,
+    /// euler angles in degrees, applied in x, y, z order
+    pub rotation: Vec3,
+    pub scale: Vec3,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform {
+            translation: Vec3::ZERO,
+            rotation: Vec3::ZERO,
+            scale: Vec3::ONE,
+        }
+    }
+}
+
+impl From<Transform> for Affine3A {
+    fn from(value: Transform) -> Self {
+        let rotation = Quat::from_euler(
+            EulerRot::ZYX,
+            value.rotation.z.to_radians(),
+            value.rotation.y.to_radians(),
+            value.rotation.x.to_radians(),
+        );
+        Affine3A::from_scale_rotation_translation(value.scale, rotation, value.translation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_scene() {
+        let scene: SceneDescription = toml::from_str(
+            r#"
+            palette = "palette"
+
+            [camera]
+            position = [0.0, 1.0, 5.0]
+            projection = { orthographic = { height = 3.0 } }
+
+            [[lights]]
+            ambient = { intensity = 0.5 }
+
+            [[nodes]]
+            name = "sphere"
+            mesh = "sphere"
+            materials = "sphere.materials"
+            transform = { translation = [1.0, 0.0, 0.0] }
+
+            [[nodes.children]]
+            name = "moon"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(Vec3::new(0., 1., 5.), scene.camera.position);
+        // unset camera fields keep their defaults
+        assert_eq!(Camera::default().far, scene.camera.far);
+        assert_eq!(1, scene.lights.len());
+        assert_eq!("moon", scene.nodes[0].children[0].name);
+        let transform = Affine3A::from(scene.nodes[0].transform);
+        assert_eq!(Vec3::X, transform.transform_point3(Vec3::ZERO));
+    }
+
+    #[test]
+    fn rotation_in_degrees() {
+        let transform = Affine3A::from(Transform {
+            rotation: Vec3::new(0., 90., 0.),
+            ..Default::default()
+        });
+        assert!(transform
+            .transform_vector3(Vec3::X)
+            .abs_diff_eq(-Vec3::Z, 1e-6));
+    }
+}
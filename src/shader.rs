@@ -0,0 +1,135 @@
+use glam::{Vec2, Vec3};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    color::{lit_color, lit_texel, LightCurve, Material},
+    texture::Texture,
+};
+
+/// Everything known about a covered pixel once all meshes have been rasterized
+#[derive(Debug, Clone, Copy)]
+pub struct Fragment<'a> {
+    pub x: usize,
+    pub y: usize,
+    /// 1 at the near plane, 0 at the far plane
+    pub depth: f32,
+    /// which mesh drawn this frame covers the pixel
+    pub mesh: usize,
+    /// index into the mesh's triangles
+    pub triangle: usize,
+    pub material: &'a Material,
+    /// the mesh's texture, if the triangle has texture coordinates
+    pub texture: Option<&'a Texture>,
+    /// interpolated world space unit normal
+    pub normal: Vec3,
+    /// world space position
+    pub position: Vec3,
+    pub uv: Vec2,
+    pub light: f32,
+}
+
+/// Turns a fragment into a palette index
+pub trait FragmentShader {
+    fn shade(&self, fragment: &Fragment) -> u8;
+}
+
+impl<F> FragmentShader for F
+where
+    F: Fn(&Fragment) -> u8,
+{
+    fn shade(&self, fragment: &Fragment) -> u8 {
+        (self)(fragment)
+    }
+}
+
+/// Picks a shade from the material with `lit_color`
+#[derive(Debug, Clone, Copy)]
+pub struct FlatLit;
+
+impl FragmentShader for FlatLit {
+    fn shade(&self, fragment: &Fragment) -> u8 {
+        lit_color(fragment.light, fragment.material)
+    }
+}
+
+fn color_index(v: f32, num_colors: usize) -> usize {
+    ((v * num_colors as f32).floor() as usize).clamp(0, num_colors - 1)
+}
+
+fn color_rounded_index(v: f32, num_colors: usize) -> usize {
+    // in index units, the transition point closest to v
+    (v * num_colors as f32).round() as usize
+}
+
+fn closest_transition(v: f32, num_colors: usize) -> f32 {
+    // in light units, the transition point closest to v
+    (color_rounded_index(v, num_colors) as f32 - 1.) / num_colors as f32
+}
+
+fn transition_distance(v: f32, num_colors: usize) -> f32 {
+    (v - closest_transition(v, num_colors)).abs()
+}
+
+const DITHER_RATIO: f32 = 0.5; // should go between 0 and 1
+
+/// Spreads the material's shades over the light range along its curve, or
+/// evenly if it has none, with a checkerboard dither where neighbouring shades meet
+#[derive(Debug, Clone, Copy)]
+pub struct DitherMask;
+
+impl FragmentShader for DitherMask {
+    fn shade(&self, fragment: &Fragment) -> u8 {
+        let (x, y, m) = (fragment.x, fragment.y, fragment.material);
+        let num_shades = m.shades.len();
+        // let num_shades = 3;
+        let scaled = m.shade_position(fragment.light, &LightCurve::Linear);
+        let c2 = color_rounded_index(closest_transition(scaled, num_shades), num_shades);
+        let c1 = (c2.saturating_sub(1)).clamp(0, num_shades - 1);
+        let transition_width = 1. / num_shades as f32;
+        if transition_distance(scaled, num_shades) <= transition_width * (DITHER_RATIO + 0.5) {
+            if (x ^ y) % 2 == 0 {
+                m.shades[c2]
+            } else {
+                m.shades[c1]
+            }
+        } else {
+            m.shades[color_index(scaled, num_shades)]
+        }
+    }
+}
+
+/// Samples the fragment's texture and lights it with `lit_texel`.
+/// Untextured fragments are passed on to the wrapped shader.
+#[derive(Debug, Clone, Copy)]
+pub struct Textured<S>(pub S);
+
+impl<S: FragmentShader> FragmentShader for Textured<S> {
+    fn shade(&self, fragment: &Fragment) -> u8 {
+        match fragment.texture {
+            Some(texture) => lit_texel(
+                texture.sample(fragment.uv),
+                fragment.light,
+                fragment.material,
+            ),
+            None => self.0.shade(fragment),
+        }
+    }
+}
+
+/// Shaders that can be picked per material in `materials.toml`, e.g.
+/// `shader = "flat_lit"`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MaterialShader {
+    FlatLit,
+    DitherMask,
+}
+
+impl FragmentShader for MaterialShader {
+    fn shade(&self, fragment: &Fragment) -> u8 {
+        match self {
+            MaterialShader::FlatLit => Textured(FlatLit).shade(fragment),
+            MaterialShader::DitherMask => Textured(DitherMask).shade(fragment),
+        }
+    }
+}
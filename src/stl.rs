@@ -0,0 +1,338 @@
+use std::{borrow::Cow, collections::HashMap, fmt, fs, path::Path, str::Utf8Error};
+
+use assets_manager::{loader::Loader, AnyCache, Asset, BoxedError};
+use glam::Vec3;
+
+use crate::{
+    color::Materials,
+    geo::{self, CullMode, IndexedTriangle, Mesh, RenderMode, Winding},
+    mtl,
+    obj::Location,
+};
+
+/// 80 byte header and the triangle count
+const HEADER_LEN: usize = 84;
+/// normal, 3 vertices and a 2 byte attribute count
+const TRIANGLE_LEN: usize = 50;
+
+#[derive(Debug)]
+pub enum StlError {
+    Io {
+        file: Option<String>,
+        source: std::io::Error,
+    },
+    Encoding {
+        file: Option<String>,
+        source: Utf8Error,
+    },
+    Syntax {
+        location: Location,
+        expected: String,
+    },
+    /// a binary file shorter or longer than its triangle count says
+    Truncated {
+        file: Option<String>,
+        triangles: usize,
+        len: usize,
+    },
+}
+
+impl StlError {
+    /// Adds the file name to the error
+    pub fn in_file(self, name: impl Into<String>) -> Self {
+        let name = Some(name.into());
+        match self {
+            StlError::Io { source, .. } => StlError::Io { file: name, source },
+            StlError::Encoding { source, .. } => StlError::Encoding { file: name, source },
+            StlError::Syntax { location, expected } => StlError::Syntax {
+                location: Location {
+                    file: name,
+                    ..location
+                },
+                expected,
+            },
+            StlError::Truncated { triangles, len, .. } => StlError::Truncated {
+                file: name,
+                triangles,
+                len,
+            },
+        }
+    }
+}
+
+impl fmt::Display for StlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file = |file: &Option<String>| file.clone().unwrap_or_else(|| "<stl>".to_string());
+        match self {
+            StlError::Io { file: name, source } => write!(f, "{}: {source}", file(name)),
+            StlError::Encoding { file: name, source } => {
+                write!(f, "{}: ASCII STL is not valid UTF-8: {source}", file(name))
+            }
+            StlError::Syntax { location, expected } => {
+                write!(f, "{location}: expected {expected}")
+            }
+            StlError::Truncated {
+                file: name,
+                triangles,
+                len,
+            } => write!(
+                f,
+                "{}: {len} bytes is the wrong size for {triangles} triangles",
+                file(name)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StlError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StlError::Io { source, .. } => Some(source),
+            StlError::Encoding { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// The triangles of an ASCII or binary .stl file, each with its own vertices
+#[derive(Debug, Clone, Default)]
+pub struct StlData {
+    pub triangles: Vec<[Vec3; 3]>,
+}
+
+impl StlData {
+    /// Welds vertices shared between triangles, which STL stores separately,
+    /// so normals are smoothed across them. Every triangle gets the first of
+    /// `materials`.
+    pub fn into_mesh(self, materials: Materials) -> Mesh {
+        let mut vertices = Vec::new();
+        // keyed by bit pattern, adding 0 turns -0 into 0 so those match too
+        let mut welded: HashMap<[u32; 3], usize> = HashMap::new();
+        let faces: Vec<(usize, usize, usize)> = self
+            .triangles
+            .iter()
+            .map(|triangle| {
+                let [a, b, c] = triangle.map(|v| {
+                    *welded
+                        .entry((v + 0.).to_array().map(f32::to_bits))
+                        .or_insert_with(|| {
+                            vertices.push(v);
+                            vertices.len() - 1
+                        })
+                });
+                (a, b, c)
+            })
+            .collect();
+        let normals = geo::vertex_normals(&vertices, &faces);
+
+        Mesh {
+            triangles: faces
+                .into_iter()
+                .map(|index| IndexedTriangle {
+                    index,
+                    normal_index: index,
+                    uv_index: None,
+                    material_index: 0,
+                })
+                .collect(),
+            vertices,
+            normals,
+            uvs: Vec::new(),
+            lines: Vec::new(),
+            materials,
+            texture: None,
+            cull_mode: CullMode::default(),
+            winding: Winding::default(),
+            render_mode: RenderMode::default(),
+        }
+    }
+}
+
+/// Reads an .stl file, see `StlData::into_mesh`
+pub fn parse(path: &Path, materials: Materials) -> Result<Mesh, StlError> {
+    let file = path.display().to_string();
+    let bytes = fs::read(path).map_err(|source| StlError::Io {
+        file: Some(file.clone()),
+        source,
+    })?;
+    let data = parse_bytes(&bytes).map_err(|e| e.in_file(file))?;
+    Ok(data.into_mesh(materials))
+}
+
+/// Parses binary or ASCII STL. Binary files may also start with "solid", so
+/// a file is only read as ASCII if its size doesn't match its triangle count.
+pub fn parse_bytes(bytes: &[u8]) -> Result<StlData, StlError> {
+    let triangle_count = bytes
+        .get(80..HEADER_LEN)
+        .map(|count| u32::from_le_bytes(count.try_into().unwrap()) as usize);
+    match triangle_count {
+        Some(count) if bytes.len() == HEADER_LEN + count * TRIANGLE_LEN => Ok(parse_binary(bytes)),
+        _ if bytes.starts_with(b"solid") => {
+            let stl_string = std::str::from_utf8(bytes)
+                .map_err(|source| StlError::Encoding { file: None, source })?;
+            parse_ascii(stl_string)
+        }
+        count => Err(StlError::Truncated {
+            file: None,
+            triangles: count.unwrap_or_default(),
+            len: bytes.len(),
+        }),
+    }
+}
+
+/// Reads the triangles of a binary file whose length has been checked
+fn parse_binary(bytes: &[u8]) -> StlData {
+    let triangles = bytes[HEADER_LEN..]
+        .chunks_exact(TRIANGLE_LEN)
+        .map(|triangle| {
+            // skips the normal, which is recomputed from the welded vertices
+            let float = |i: usize| {
+                let start = 12 + i * 4;
+                f32::from_le_bytes(triangle[start..start + 4].try_into().unwrap())
+            };
+            let vertex = |v: usize| Vec3::new(float(v * 3), float(v * 3 + 1), float(v * 3 + 2));
+            [vertex(0), vertex(1), vertex(2)]
+        })
+        .collect();
+    StlData { triangles }
+}
+
+pub fn parse_ascii(stl_string: &str) -> Result<StlData, StlError> {
+    let mut triangles = Vec::new();
+    // vertices of the current `outer loop`
+    let mut polygon: Option<Vec<Vec3>> = None;
+    for (i, text) in stl_string.lines().enumerate() {
+        let syntax = |token: &str, expected: &str| StlError::Syntax {
+            location: Location {
+                file: None,
+                line: i + 1,
+                token: token.to_string(),
+            },
+            expected: expected.to_string(),
+        };
+        let mut tokens = text.split_whitespace();
+        match tokens.next() {
+            Some("outer") => polygon = Some(Vec::new()),
+            Some("vertex") => {
+                let polygon = polygon
+                    .as_mut()
+                    .ok_or_else(|| syntax(text.trim(), "outer loop before vertex"))?;
+                let xyz = tokens
+                    .map(|s| s.parse::<f32>().map_err(|_| syntax(s, "a float")))
+                    .collect::<Result<Vec<_>, _>>()?;
+                match xyz[..] {
+                    [x, y, z] => polygon.push(Vec3::new(x, y, z)),
+                    _ => return Err(syntax(text.trim(), "3 floats")),
+                }
+            }
+            Some("endloop") => {
+                let polygon = polygon
+                    .take()
+                    .ok_or_else(|| syntax(text.trim(), "outer loop before endloop"))?;
+                if polygon.len() < 3 {
+                    return Err(syntax(text.trim(), "at least 3 vertices in the loop"));
+                }
+                // some exporters write polygons, which are fanned like obj faces
+                for pair in polygon[1..].windows(2) {
+                    triangles.push([polygon[0], pair[0], pair[1]]);
+                }
+            }
+            // solid names and facet normals aren't needed
+            _ => {}
+        }
+    }
+    Ok(StlData { triangles })
+}
+
+pub struct StlLoader;
+
+impl Loader<StlData> for StlLoader {
+    fn load(content: Cow<[u8]>, _ext: &str) -> Result<StlData, BoxedError> {
+        Ok(parse_bytes(&content)?)
+    }
+}
+
+impl Asset for StlData {
+    const EXTENSION: &'static str = "stl";
+    type Loader = StlLoader;
+}
+
+/// Loads the .stl file `id` from the cache. Its faces use the first material
+/// in the toml file `materials_id`, or a gray ramp of the palette `palette_id`.
+pub fn load_mesh(
+    cache: AnyCache,
+    id: &str,
+    materials_id: Option<&str>,
+    palette_id: &str,
+) -> Result<Mesh, BoxedError> {
+    let data = cache.load::<StlData>(id)?.cloned();
+    let materials = mtl::load_materials(cache, materials_id, palette_id)?;
+    Ok(data.into_mesh(materials))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SQUARE: &str = "
+        solid square
+          facet normal 0 0 1
+            outer loop
+              vertex 0 0 0
+              vertex 1 0 0
+              vertex 1 1 0
+            endloop
+          endfacet
+          facet normal 0 0 1
+            outer loop
+              vertex 0 0 0
+              vertex 1 1 0
+              vertex -0 1 0
+            endloop
+          endfacet
+        endsolid square
+        ";
+
+    #[test]
+    fn welds_ascii_vertices() {
+        let data = parse_bytes(SQUARE.trim_start().as_bytes()).unwrap();
+        assert_eq!(2, data.triangles.len());
+        let mesh = data.into_mesh(Materials::default());
+        assert_eq!(4, mesh.vertices.len());
+        assert_eq!((0, 2, 3), mesh.triangles[1].index);
+        assert_eq!(Vec3::Z, mesh.normals[0]);
+    }
+
+    #[test]
+    fn binary_matches_ascii() {
+        let ascii = parse_ascii(SQUARE).unwrap();
+        // binary files may start with "solid" too
+        let mut bytes = b"solid but binary".to_vec();
+        bytes.resize(80, 0);
+        bytes.extend((ascii.triangles.len() as u32).to_le_bytes());
+        for triangle in &ascii.triangles {
+            bytes.extend(Vec3::Z.to_array().map(f32::to_le_bytes).concat());
+            for vertex in triangle {
+                bytes.extend(vertex.to_array().map(f32::to_le_bytes).concat());
+            }
+            bytes.extend([0, 0]);
+        }
+        let binary = parse_bytes(&bytes).unwrap();
+        assert_eq!(ascii.triangles, binary.triangles);
+
+        bytes.pop();
+        bytes[..5].copy_from_slice(b"model");
+        assert!(matches!(
+            parse_bytes(&bytes),
+            Err(StlError::Truncated { triangles: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn errors_have_locations() {
+        let error = parse_ascii("solid\nouter loop\nvertex 0 0 x\n")
+            .unwrap_err()
+            .in_file("square.stl");
+        assert_eq!("square.stl:3: `x`: expected a float", error.to_string());
+    }
+}
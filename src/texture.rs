@@ -0,0 +1,137 @@
+use assets_manager::Asset;
+use glam::Vec2;
+use serde::Deserialize;
+
+use crate::color::{Material, Palette};
+
+/// An indexed color image, `pixels` holds palette indices row by row from the top left
+#[derive(Deserialize, Debug, Clone, Asset)]
+#[asset_format = "toml"]
+#[serde(try_from = "TextureDescription")]
+pub struct Texture {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+/// A `Texture` as written in toml, before its size is checked
+#[derive(Deserialize)]
+struct TextureDescription {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl TryFrom<TextureDescription> for Texture {
+    type Error = String;
+
+    fn try_from(value: TextureDescription) -> Result<Self, Self::Error> {
+        let TextureDescription {
+            width,
+            height,
+            pixels,
+        } = value;
+        if width == 0 || height == 0 {
+            return Err(format!("a {width}x{height} texture has no pixels"));
+        }
+        if width.checked_mul(height) != Some(pixels.len()) {
+            return Err(format!(
+                "{} pixels for a {width}x{height} texture",
+                pixels.len()
+            ));
+        }
+        Ok(Texture {
+            width,
+            height,
+            pixels,
+        })
+    }
+}
+
+impl Texture {
+    /// Nearest neighbour lookup. Coordinates wrap around, and `v` points up
+    /// like in OBJ files.
+    pub fn sample(&self, uv: Vec2) -> u8 {
+        let x = (uv.x * self.width as f32).floor() as i64;
+        let y = ((1. - uv.y) * self.height as f32).floor() as i64;
+        let x = x.rem_euclid(self.width as i64) as usize;
+        let y = y.rem_euclid(self.height as i64) as usize;
+        self.pixels[y * self.width + x]
+    }
+
+    /// The first texel that isn't a color of `palette`, as its index into
+    /// `pixels` and its palette index
+    pub fn validate(&self, palette: &Palette) -> Option<(usize, u8)> {
+        let pixel = self
+            .pixels
+            .iter()
+            .position(|&index| index as usize >= palette.colors.len())?;
+        Some((pixel, self.pixels[pixel]))
+    }
+
+    /// Logs the error of `validate` for the texture `id`, and swaps every
+    /// texel outside `palette` for the color of `Material::error`, so that
+    /// the texture can still be drawn
+    pub fn checked(mut self, palette: &Palette, id: &str) -> Self {
+        if let Some((pixel, index)) = self.validate(palette) {
+            log::error!(
+                "texture {id}: pixel {pixel} is color {index}, but the palette only has {} colors",
+                palette.colors.len()
+            );
+            let error_color = Material::error(palette).shades[0];
+            for texel in &mut self.pixels {
+                if *texel as usize >= palette.colors.len() {
+                    *texel = error_color;
+                }
+            }
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texture(toml: &str) -> Result<Texture, toml::de::Error> {
+        toml::from_str(toml)
+    }
+
+    #[test]
+    fn sample_flips_v_and_wraps() {
+        // 1 2 3
+        // 4 5 6
+        let t = texture("width = 3\nheight = 2\npixels = [1, 2, 3, 4, 5, 6]").unwrap();
+        // v = 0 is the bottom row
+        assert_eq!(4, t.sample(Vec2::new(0.1, 0.1)));
+        assert_eq!(3, t.sample(Vec2::new(0.9, 0.9)));
+        assert_eq!(5, t.sample(Vec2::new(0.5, 0.25)));
+        // whole numbers of tiles away sample the same texel
+        assert_eq!(4, t.sample(Vec2::new(2.1, -0.9)));
+        assert_eq!(3, t.sample(Vec2::new(-0.1, 1.9)));
+        // v = 0 is where the tile below starts, so it wraps to the top row
+        assert_eq!(1, t.sample(Vec2::new(0.0, 0.0)));
+        assert_eq!(4, t.sample(Vec2::new(0.0, 1e-3)));
+    }
+
+    #[test]
+    fn texels_outside_palette() {
+        let palette = Palette {
+            colors: vec![0x000000FF, 0xFF00FFFF, 0xFFFFFFFF],
+        };
+        let t = texture("width = 2\nheight = 2\npixels = [0, 2, 16, 255]").unwrap();
+        assert_eq!(Some((2, 16)), t.validate(&palette));
+        let checked = t.checked(&palette, "tiles");
+        assert_eq!(None, checked.validate(&palette));
+        assert_eq!(vec![0, 2, 1, 1], checked.pixels);
+    }
+
+    #[test]
+    fn sizes_are_checked() {
+        assert!(texture("width = 0\nheight = 2\npixels = []").is_err());
+        assert!(texture("width = 2\nheight = 0\npixels = []").is_err());
+        assert!(texture("width = 2\nheight = 2\npixels = [1, 2, 3]").is_err());
+        assert!(texture("width = 2\nheight = 2\npixels = [1, 2, 3, 4, 5]").is_err());
+        assert!(texture("width = 1\nheight = 1\npixels = [7]").is_ok());
+    }
+}